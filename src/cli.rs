@@ -1,37 +1,290 @@
-use std::env;
+use crate::{
+    config::Config,
+    server::{self, Listen},
+    telemetry,
+    utils::send_request::send_request,
+};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use eyre::{eyre, Result};
+use hyper::{
+    header::{AUTHORIZATION, HOST},
+    Body, Method, StatusCode,
+};
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, BufRead, IsTerminal},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+};
+use tokio::net::{TcpStream, UnixStream};
 
 mod connection;
+mod doctor;
+mod healthcheck;
 mod oneshot;
-mod server;
 mod test;
 
-fn usage() {
-    println!(
-        "
-Usage: {0} [command] [args, ...]
-
-Commands:
-  server        Run webserver and websockets
-  connection    List, add and remove connections
-  test          Test your endpoint/uuid
-
-Run '{0} [command] --help' for more information on a command.
-",
-        env::args().nth(0).unwrap()
-    );
-}
-
-pub async fn cli() {
-    let mut args = env::args();
-    args.next();
-    match args.next() {
-        Some(cmd) if cmd == "oneshot" || cmd == "o" => oneshot::oneshot(args).await,
-        Some(cmd) if cmd == "connection" || cmd == "c" => connection::connection(args).await,
-        Some(cmd) if cmd == "server" || cmd == "s" => server::server(args).await,
-        Some(cmd) if cmd == "test" || cmd == "t" => test::test(args).await,
-        Some(cmd) if cmd == "--version" => {
-            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
-        }
-        _ => usage(),
+/// Get Signal notifications via UnifiedPush.
+#[derive(Parser)]
+#[command(name = "mollysocket", version)]
+struct Cli {
+    /// Configuration file, the default location is used if not set
+    #[arg(short, long, global = true, env = "MOLLY_CONF")]
+    config: Option<PathBuf>,
+    /// Database, overrides the one of the configuration file
+    #[arg(long, global = true)]
+    db: Option<String>,
+    /// Log filter, such as `info` or `mollysocket=debug`, overrides RUST_LOG
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// Output format
+    #[arg(short, long, global = true, value_enum, default_value_t = Output::Table)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+/// Exit code of the `test` and `doctor` commands when a check fails.
+const CHECK_FAILED: u8 = 3;
+
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run webserver and websockets
+    #[command(alias = "s")]
+    Server,
+    /// List, add and remove connections
+    #[command(alias = "c", subcommand)]
+    Connection(connection::Command),
+    /// Test your endpoint/uuid
+    #[command(alias = "t", subcommand)]
+    Test(test::Command),
+    /// Diagnose a stored connection, step by step
+    ///
+    /// Checks the UUID and the endpoint are allowed, the state of the connection, that
+    /// Signal server accepts its credentials and that the push server accepts a test
+    /// notification. Exits with 3 if a step fails.
+    Doctor { uuid: String },
+    /// Run a single connection, without web server nor database
+    #[command(alias = "o")]
+    Oneshot(oneshot::Args),
+    /// Check the health of the running server
+    ///
+    /// Query the health endpoint of the local server. The address is read like
+    /// the server does: admin_listen, listen, or ROCKET_ADDRESS and ROCKET_PORT.
+    /// Exits with a non-zero code if the server is not healthy.
+    Healthcheck(healthcheck::Args),
+    /// Print the completions for a shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page
+    Man,
+}
+
+/// Prompt for the password on a terminal, else read the first line of stdin,
+/// so it isn't visible in the process list nor the shell history.
+pub fn read_password() -> Result<String> {
+    let password = if io::stdin().is_terminal() {
+        rpassword::prompt_password("Password: ")?
+    } else {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if password.is_empty() {
+        return Err(eyre!("The password is empty"));
+    }
+    Ok(password)
+}
+
+/// Address of the local server, for the health and admin routes.
+pub enum ServerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            ServerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The address is read like the server does: admin_listen, listen, or ROCKET_ADDRESS
+/// and ROCKET_PORT.
+pub fn server_addr(config: &Config) -> Result<ServerAddr> {
+    let listen = config
+        .user_cfg
+        .admin_listen
+        .as_ref()
+        .or(config.user_cfg.listen.as_ref())
+        .map(|listen| listen.parse::<Listen>())
+        .transpose()?;
+    let addr = match listen {
+        Some(Listen::Tcp(addr)) => addr,
+        Some(Listen::Unix(path)) => return Ok(ServerAddr::Unix(path)),
+        None => {
+            let rocket_cfg = rocket::Config::figment().extract::<rocket::Config>()?;
+            SocketAddr::new(rocket_cfg.address, rocket_cfg.port)
+        }
+    };
+    // The server may listen on every interface, we query it locally
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Ok(ServerAddr::Tcp(SocketAddr::new(ip, addr.port())))
+}
+
+/// Send a request to the local server, on its TCP or unix socket, with the admin
+/// token if given. Returns the status and the body of the response.
+pub async fn server_request(
+    config: &Config,
+    method: Method,
+    path: &str,
+    token: Option<&str>,
+) -> Result<(StatusCode, String)> {
+    let addr = server_addr(config)?;
+    let host = match &addr {
+        ServerAddr::Tcp(addr) => addr.to_string(),
+        ServerAddr::Unix(_) => String::from("localhost"),
+    };
+    let mut req = hyper::Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, host);
+    if let Some(token) = token {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let req = req.body(Body::empty())?;
+    let connect_error = |e| eyre!("Could not connect to {}: {}", addr, e);
+    let resp = match &addr {
+        ServerAddr::Tcp(tcp) => {
+            send_request(TcpStream::connect(tcp).await.map_err(connect_error)?, req).await?
+        }
+        ServerAddr::Unix(path) => {
+            send_request(UnixStream::connect(path).await.map_err(connect_error)?, req).await?
+        }
+    };
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+pub async fn cli() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    // Handled before loading the config, which is created if missing
+    match cli.command {
+        Command::Completions { shell } => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(shell, &mut cmd, name, &mut io::stdout());
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Man => {
+            clap_mangen::Man::new(Cli::command()).render(&mut io::stdout())?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => (),
+    }
+
+    let mut config = Config::load_file(cli.config)?;
+    if let Some(db) = cli.db {
+        config.user_cfg.db = db;
+    }
+    let _telemetry = telemetry::init(config.user_cfg.log_format, cli.log_level.as_deref())?;
+    log::debug!("Config file: {}", config.file_name());
+    let config = Arc::new(config);
+
+    match cli.command {
+        Command::Server => server::run(config).await?,
+        Command::Connection(command) => {
+            connection::connection(command, &config, cli.output).await?
+        }
+        Command::Test(command) => {
+            if !test::test(command, &config, cli.output).await? {
+                return Ok(ExitCode::from(CHECK_FAILED));
+            }
+        }
+        Command::Doctor { uuid } => {
+            if !doctor::doctor(&uuid, config, cli.output).await? {
+                return Ok(ExitCode::from(CHECK_FAILED));
+            }
+        }
+        Command::Oneshot(args) => oneshot::oneshot(args, config).await?,
+        Command::Healthcheck(args) => healthcheck::healthcheck(args, &config).await?,
+        Command::Completions { .. } | Command::Man => (),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn keep_aliases() {
+        for alias in ["c", "s", "t", "o"] {
+            let found = Cli::command()
+                .get_subcommands()
+                .any(|cmd| cmd.get_all_aliases().any(|a| a == alias));
+            assert!(found, "missing alias {}", alias);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_unix_socket() {
+        use crate::config::UserConfig;
+        use hyper::{server::conn::Http, service::service_fn, Response};
+        use std::convert::Infallible;
+
+        let path = std::env::temp_dir().join(format!("ms-cli-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|req: hyper::Request<Body>| async move {
+                let auth = req.headers().get(AUTHORIZATION).cloned();
+                let body = format!("{} {} {:?}", req.method(), req.uri(), auth);
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            });
+            Http::new().serve_connection(stream, service).await.unwrap();
+        });
+
+        let config = Config::load(Some(UserConfig {
+            listen: Some(format!("unix:{}", path.display())),
+            ..Default::default()
+        }))
+        .unwrap();
+        let (status, body) = server_request(&config, Method::POST, "/admin/x", Some("tok"))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "POST /admin/x Some(\"Bearer tok\")");
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,131 +1,354 @@
 use crate::{
+    cli::{print_json, read_password, server_request, Output},
+    config::Config,
     db::{self, OptTime},
-    CONFIG,
+    server::{self, ConnectionState},
+    utils::endpoint_host::endpoint_host,
+    ws::PushPolicy,
+};
+use clap::Subcommand;
+use eyre::{eyre, Result};
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::env::{self, Args};
-
-fn usage() {
-    println!(
-        "
-Usage: {} connection [command] [args, ...]
-
-Commands:            
-  add [uuid] [device_id] [password] [endpoint]
-  list
-  rm [uuid]
-",
-        env::args().nth(0).unwrap()
-    );
-}
 
-pub async fn connection(args: Args) {
-    let argv: Vec<String> = args.collect();
-    if argv.iter().any(|arg| arg == "--help" || arg == "-h") {
-        return usage();
-    };
-    match argv.get(0) {
-        Some(cmd) if cmd == "add" || cmd == "a" => add(argv).await,
-        Some(cmd) if cmd == "rm" || cmd == "r" => rm(argv),
-        Some(cmd) if cmd == "list" || cmd == "l" => list(),
-        _ => return usage(),
-    };
+#[derive(Subcommand)]
+pub enum Command {
+    /// Add a connection, the password is read from stdin or prompted
+    #[command(alias = "a")]
+    Add {
+        uuid: String,
+        device_id: u32,
+        endpoint: String,
+    },
+    /// List the connections
+    #[command(alias = "l")]
+    List,
+    /// Remove a connection
+    #[command(alias = "r")]
+    Rm { uuid: String },
+    /// Show a connection
+    Show { uuid: String },
+    /// Stop handling a connection, without removing it
+    Disable { uuid: String },
+    /// Handle a disabled connection again
+    Enable { uuid: String },
+    /// Mark a connection as forbidden by Signal server
+    Forbid { uuid: String },
+    /// Handle a connection marked as forbidden again
+    Unforbid { uuid: String },
+    /// Change the push endpoint of a connection
+    SetEndpoint { uuid: String, endpoint: String },
+    /// Change when the envelopes of a connection trigger a push
+    ///
+    /// The policy is `every`, `window:<secs>` or `quiet:<secs>`, followed by `:trailing`
+    /// to push at the end of the window or of the quiet period if envelopes were dropped.
+    /// `default` uses the push_policy of the config.
+    SetPushPolicy { uuid: String, policy: String },
 }
 
-async fn add(mut argv: Vec<String>) {
-    argv.remove(0);
-    let uuid = match argv.get(0) {
-        Some(argv1) => {
-            if CONFIG.is_uuid_valid(argv1) {
-                argv1
-            } else {
-                println!("UUID invalid or forbidden: {}", argv1);
-                return usage();
-            }
+pub async fn connection(command: Command, config: &Config, output: Output) -> Result<()> {
+    match command {
+        Command::Add {
+            uuid,
+            device_id,
+            endpoint,
+        } => add(config, uuid, device_id, endpoint).await,
+        Command::List => list(config, output).await,
+        Command::Rm { uuid } => rm(config, &uuid).await,
+        Command::Show { uuid } => show(config, &uuid, output).await,
+        Command::Disable { uuid } => {
+            update(config, &uuid, "disabled", |co| co.disabled = true).await
         }
-        _ => {
-            return usage();
+        Command::Enable { uuid } => {
+            update(config, &uuid, "enabled", |co| co.disabled = false).await
         }
-    }
-    .clone();
-    let device_id = match argv.get(1) {
-        Some(argv2) => {
-            if is_valid_int(argv2) {
-                argv2.parse::<u32>().unwrap()
-            } else {
-                println!("Device_id invalid: {}", argv2);
-                return usage();
-            }
+        Command::Forbid { uuid } => {
+            update(config, &uuid, "forbidden", |co| co.forbidden = true).await
         }
-        _ => {
-            return usage();
+        Command::Unforbid { uuid } => {
+            update(config, &uuid, "unforbidden", |co| co.forbidden = false).await
         }
-    }
-    .clone();
-    let password = match argv.get(2) {
-        Some(argv3) => argv3,
-        _ => {
-            return usage();
-        }
-    }
-    .clone();
-    let endpoint = match argv.get(3) {
-        Some(argv4) => {
-            if CONFIG.is_endpoint_valid(argv4).await {
-                argv4
-            } else {
-                println!("Endpoint invalid or forbidden: {}", argv4);
-                return usage();
+        Command::SetEndpoint { uuid, endpoint } => {
+            if !config.is_endpoint_valid(&endpoint).await {
+                return Err(eyre!("Endpoint invalid or forbidden: {}", endpoint));
             }
+            update(config, &uuid, "updated", |co| {
+                co.endpoint = endpoint;
+                co.endpoint_gone = false;
+            })
+            .await
         }
-        _ => {
-            return usage();
+        Command::SetPushPolicy { uuid, policy } => {
+            let policy = match policy.as_str() {
+                "default" => None,
+                policy => Some(policy.parse::<PushPolicy>()?),
+            };
+            update(config, &uuid, "updated", |co| co.push_policy = policy).await
         }
     }
-    .clone();
-    let _ = db::MollySocketDb::new().unwrap().add(&db::Connection {
+}
+
+async fn add(config: &Config, uuid: String, device_id: u32, endpoint: String) -> Result<()> {
+    if !config.is_uuid_valid(&uuid) {
+        return Err(eyre!("UUID invalid or forbidden: {}", uuid));
+    }
+    if !config.is_endpoint_valid(&endpoint).await {
+        return Err(eyre!("Endpoint invalid or forbidden: {}", endpoint));
+    }
+    let password = read_password()?;
+    db::MollySocketDb::new(&config.user_cfg.db)?.add(&db::Connection {
         uuid: uuid.clone(),
         device_id,
         password,
         endpoint,
         forbidden: false,
         last_registration: OptTime(None),
-    });
+        endpoint_gone: false,
+        disabled: false,
+        push_policy: None,
+    })?;
     println!("Connection for {} added.", uuid);
+    notify(config, &uuid).await;
+    Ok(())
+}
+
+/// A connection as printed, without its password.
+#[derive(Serialize)]
+struct ConnectionOutput<'a> {
+    uuid: &'a str,
+    device_id: u32,
+    state: String,
+    /// `server` if the state was given by the running server, else `stored`.
+    state_source: &'static str,
+    /// Seconds since the epoch.
+    last_registration: Option<u64>,
+    endpoint: &'a str,
+    endpoint_host: Option<String>,
+    /// None for the policy of the config.
+    push_policy: Option<PushPolicy>,
+}
+
+impl<'a> ConnectionOutput<'a> {
+    /// `states` are the states given by the running server, None if it isn't running.
+    fn new(co: &'a db::Connection, states: Option<&HashMap<String, String>>) -> Self {
+        let stored = || {
+            ConnectionState::stored(co)
+                .unwrap_or(ConnectionState::Pending)
+                .as_str()
+                .to_string()
+        };
+        let (state, state_source) = match states {
+            // Not tracked yet by the server, as `/admin/connections/<uuid>`
+            Some(states) => (
+                states.get(&co.uuid).cloned().unwrap_or_else(stored),
+                "server",
+            ),
+            None => (stored(), "stored"),
+        };
+        Self {
+            uuid: &co.uuid,
+            device_id: co.device_id,
+            state,
+            state_source,
+            last_registration: co
+                .last_registration
+                .0
+                .map(|_| u64::from(&co.last_registration)),
+            endpoint: &co.endpoint,
+            endpoint_host: endpoint_host(&co.endpoint),
+            push_policy: co.push_policy,
+        }
+    }
+}
+
+/// A connection as listed by the running server.
+#[derive(Deserialize)]
+struct ServerConnection {
+    uuid: String,
+    state: String,
 }
 
-fn list() {
-    db::MollySocketDb::new()
-        .unwrap()
-        .list()
-        .unwrap()
+/// The state of the connections given by the running server, with the control
+/// socket, or else the admin API. None if the server could not be asked.
+async fn server_states(config: &Config) -> Option<HashMap<String, String>> {
+    let mut connections: Option<Vec<ServerConnection>> = None;
+    if config.user_cfg.control_socket {
+        match server::request_list(config).await {
+            Ok(json) => connections = serde_json::from_str(&json).ok(),
+            Err(e) => log::debug!("Could not use the control socket: {}", e),
+        }
+    }
+    if connections.is_none() {
+        let token = config.user_cfg.admin_token.as_ref()?;
+        let (status, body) = server_request(config, Method::GET, "/admin/connections", Some(token))
+            .await
+            .ok()?;
+        if !status.is_success() {
+            return None;
+        }
+        connections = serde_json::from_str(&body).ok();
+    }
+    Some(
+        connections?
+            .into_iter()
+            .map(|co| (co.uuid, co.state))
+            .collect(),
+    )
+}
+
+async fn list(config: &Config, output: Output) -> Result<()> {
+    let connections = db::MollySocketDb::new(&config.user_cfg.db)?.list()?;
+    let states = server_states(config).await;
+    let connections: Vec<_> = connections
         .iter()
-        .for_each(|connection| {
-            dbg!(&connection);
-        });
+        .map(|co| ConnectionOutput::new(co, states.as_ref()))
+        .collect();
+    match output {
+        Output::Json => print_json(&connections)?,
+        Output::Table => {
+            println!(
+                "{:<36}  {:>6}  {:<13}  {:<17}  ENDPOINT HOST",
+                "UUID", "DEVICE", "STATE", "LAST REGISTRATION"
+            );
+            for co in connections {
+                println!(
+                    "{:<36}  {:>6}  {:<13}  {:<17}  {}",
+                    co.uuid,
+                    co.device_id,
+                    co.state,
+                    co.last_registration
+                        .map(ago)
+                        .unwrap_or_else(|| "never".into()),
+                    co.endpoint_host.as_deref().unwrap_or("-"),
+                );
+            }
+            if states.is_none() {
+                println!(
+                    "The server is not running or could not be asked: the stored states are shown."
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How long ago `secs` since the epoch was, such as `3h ago`.
+fn ago(secs: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let elapsed = now.saturating_sub(secs);
+    match elapsed {
+        0..=59 => format!("{}s ago", elapsed),
+        60..=3599 => format!("{}m ago", elapsed / 60),
+        3600..=86399 => format!("{}h ago", elapsed / 3600),
+        _ => format!("{}d ago", elapsed / 86400),
+    }
+}
+
+async fn rm(config: &Config, uuid: &str) -> Result<()> {
+    db::MollySocketDb::new(&config.user_cfg.db)?.rm(uuid)?;
+    println!("Connection for {} successfully removed.", uuid);
+    notify(config, uuid).await;
+    Ok(())
 }
 
-fn rm(mut argv: Vec<String>) {
-    argv.remove(0);
-    let uuid = match argv.get(0) {
-        Some(argv1) => {
-            if CONFIG.is_uuid_valid(argv1) {
-                argv1
-            } else {
-                println!("UUID invalid or forbidden: {}", argv1);
-                return usage();
+async fn show(config: &Config, uuid: &str, output: Output) -> Result<()> {
+    let co = get(&db::MollySocketDb::new(&config.user_cfg.db)?, uuid)?;
+    let states = server_states(config).await;
+    let co = ConnectionOutput::new(&co, states.as_ref());
+    match output {
+        Output::Json => print_json(&co)?,
+        Output::Table => {
+            println!("UUID:              {}", co.uuid);
+            println!("Device id:         {}", co.device_id);
+            match co.state_source {
+                "stored" => println!("State:             {} (stored)", co.state),
+                _ => println!("State:             {}", co.state),
+            }
+            println!(
+                "Last registration: {}",
+                co.last_registration
+                    .map(ago)
+                    .unwrap_or_else(|| "never".into())
+            );
+            println!("Endpoint:          {}", co.endpoint);
+            match co.push_policy {
+                Some(policy) => println!("Push policy:       {}", policy),
+                None => println!(
+                    "Push policy:       {} (default)",
+                    config.user_cfg.push_policy
+                ),
             }
         }
-        _ => {
-            return usage();
+    }
+    Ok(())
+}
+
+/// Apply `change` to a stored connection, and to the running server if possible.
+async fn update<F>(config: &Config, uuid: &str, done: &str, change: F) -> Result<()>
+where
+    F: FnOnce(&mut db::Connection),
+{
+    let db = db::MollySocketDb::new(&config.user_cfg.db)?;
+    let mut co = get(&db, uuid)?;
+    change(&mut co);
+    db.add(&co)?;
+    println!("Connection for {} {}.", uuid, done);
+    notify(config, uuid).await;
+    Ok(())
+}
+
+fn get(db: &db::MollySocketDb, uuid: &str) -> Result<db::Connection> {
+    db.get(uuid)
+        .map_err(|_| eyre!("No connection is registered with this UUID: {}", uuid))
+}
+
+/// Ask the running server to reload the connection, with the control socket,
+/// or else the admin API.
+async fn notify(config: &Config, uuid: &str) {
+    if config.user_cfg.control_socket {
+        match server::request_reload(config, uuid).await {
+            Ok(()) => {
+                println!("The running server has been notified.");
+                return;
+            }
+            Err(e) => log::debug!("Could not use the control socket: {}", e),
         }
+    }
+    let Some(token) = &config.user_cfg.admin_token else {
+        println!("The server is not running or could not be notified, restart it for the change to take effect.");
+        return;
     };
-    db::MollySocketDb::new().unwrap().rm(uuid).unwrap();
-    println!("Connection for {} successfully removed.", uuid)
+    let path = format!("/admin/connections/{}/reload", uuid);
+    match server_request(config, Method::POST, &path, Some(token)).await {
+        Ok((status, _)) if status.is_success() => println!("The running server has been notified."),
+        Ok((status, _)) => println!(
+            "The server answered {}. Restart it for the change to take effect.",
+            status
+        ),
+        Err(_) => println!("The server is not running, the change will be applied when it starts."),
+    }
 }
 
-fn is_valid_int(value: &str) -> bool {
-    match value.parse::<u32>() {
-        Ok(_) => true,
-        Err(_) => false,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_ago() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(ago(now), "0s ago");
+        assert_eq!(ago(now - 120), "2m ago");
+        assert_eq!(ago(now - 3 * 86400), "3d ago");
+        assert_eq!(ago(now + 60), "0s ago");
     }
 }
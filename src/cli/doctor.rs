@@ -0,0 +1,229 @@
+use crate::{
+    cli::{print_json, Output},
+    config::Config,
+    db::{Connection, MollySocketDb},
+    server::ConnectionState,
+    utils::test_push::{self, is_endpoint_gone, TestPush},
+    ws::{check_credentials, CheckCredentials},
+};
+use eyre::Result;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Failed,
+    /// Not run, because of a previous failure.
+    Skipped,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Failed => "failed",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Step {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    /// What to do about a failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+impl Step {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn failed(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Failed,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Skipped,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    config_file: String,
+    uuid: &'a str,
+    ok: bool,
+    steps: Vec<Step>,
+}
+
+/// Diagnose a stored connection, step by step. Returns whether every step succeeded.
+pub async fn doctor(uuid: &str, config: Arc<Config>, output: Output) -> Result<bool> {
+    let steps = match MollySocketDb::new(&config.user_cfg.db)?.get(uuid) {
+        Ok(co) => diagnose(&config, &co).await,
+        Err(_) => vec![Step::failed(
+            "connection",
+            "No connection is registered with this UUID",
+            "Register again from Molly, or add it with `mollysocket connection add`.",
+        )],
+    };
+    let report = Report {
+        config_file: config.file_name(),
+        uuid,
+        ok: steps.iter().all(|step| step.status == Status::Ok),
+        steps,
+    };
+    if output == Output::Json {
+        print_json(&report)?;
+        return Ok(report.ok);
+    }
+
+    println!("Config file: {}", report.config_file);
+    for step in &report.steps {
+        println!(
+            "[{:<7}] {:<10}  {}",
+            step.status.as_str(),
+            step.name,
+            step.detail
+        );
+        if let Some(hint) = &step.hint {
+            println!("            -> {}", hint);
+        }
+    }
+    Ok(report.ok)
+}
+
+async fn diagnose(config: &Arc<Config>, co: &Connection) -> Vec<Step> {
+    let mut steps = vec![Step::ok(
+        "connection",
+        format!("Stored, with the device id {}", co.device_id),
+    )];
+
+    steps.push(if config.is_uuid_valid(&co.uuid) {
+        Step::ok("uuid", "Allowed")
+    } else {
+        Step::failed(
+            "uuid",
+            "Not allowed",
+            "Add the UUID, or `*`, to allowed_uuids.",
+        )
+    });
+
+    steps.push(match ConnectionState::stored(co) {
+        None => Step::ok("state", "The connection can be started"),
+        Some(ConnectionState::Disabled) => Step::failed(
+            "state",
+            "Disabled",
+            format!("Run `mollysocket connection enable {}`.", co.uuid),
+        ),
+        Some(ConnectionState::Forbidden) => Step::failed(
+            "state",
+            "Forbidden by Signal server",
+            format!(
+                "If the signal step succeeds, run `mollysocket connection unforbid {}`, else register again from Molly.",
+                co.uuid
+            ),
+        ),
+        Some(state) => Step::failed(
+            "state",
+            state.as_str().replace('_', " "),
+            format!(
+                "Register again from Molly, or run `mollysocket connection set-endpoint {} <endpoint>`.",
+                co.uuid
+            ),
+        ),
+    });
+
+    let endpoint_valid = config.is_endpoint_valid(&co.endpoint).await;
+    steps.push(if endpoint_valid {
+        Step::ok("endpoint", format!("{} is allowed", co.endpoint))
+    } else if config
+        .user_cfg
+        .allowed_endpoints
+        .contains(&String::from("*"))
+    {
+        Step::failed(
+            "endpoint",
+            format!("{} does not resolve to a global IP", co.endpoint),
+            "Add the endpoint to allowed_endpoints if the push server is on your local network.",
+        )
+    } else {
+        Step::failed(
+            "endpoint",
+            format!("{} is not allowed", co.endpoint),
+            format!(
+                "Add the endpoint to allowed_endpoints, currently: {}.",
+                config.user_cfg.allowed_endpoints.join(", ")
+            ),
+        )
+    });
+
+    steps.push(check_signal(config, co).await);
+
+    steps.push(if endpoint_valid {
+        check_push(config, &co.endpoint).await
+    } else {
+        Step::skipped("push", "The endpoint is not allowed")
+    });
+    steps
+}
+
+/// Open a websocket to Signal server with the stored credentials.
+async fn check_signal(config: &Config, co: &Connection) -> Step {
+    match check_credentials(config, &co.uuid, co.device_id, &co.password).await {
+        CheckCredentials::Ok => Step::ok("signal", "Signal server accepted the credentials"),
+        CheckCredentials::Forbidden(status) => Step::failed(
+            "signal",
+            format!("Signal server refused the credentials ({})", status),
+            "The linked device has probably been removed: register again from Molly.",
+        ),
+        CheckCredentials::Unreachable(e) => Step::failed(
+            "signal",
+            format!("Could not reach Signal server: {}", e),
+            "Check the network and the DNS resolution of this host, or increase check_credentials_timeout.",
+        ),
+    }
+}
+
+async fn check_push(config: &Config, endpoint: &str) -> Step {
+    match test_push::test_push(config, endpoint).await {
+        TestPush::Accepted(id) => Step::ok(
+            "push",
+            format!("A test notification has been sent, its id is {}", id),
+        ),
+        TestPush::Unreachable => Step::failed(
+            "push",
+            "The push server is unreachable",
+            "Check the push server is up and reachable from this host.",
+        ),
+        TestPush::Rejected(status) if is_endpoint_gone(status) => Step::failed(
+            "push",
+            format!("The push server answered {}: the endpoint is gone", status),
+            "Register again from Molly, after checking the distributor app is installed.",
+        ),
+        TestPush::Rejected(status) => Step::failed(
+            "push",
+            format!("The push server answered {}", status),
+            "Check the logs of the push server.",
+        ),
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    cli::{server_addr, server_request},
+    config::Config,
+};
+use eyre::{eyre, Result};
+use hyper::Method;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[arg(value_enum, default_value_t = Check::Ready)]
+    check: Check,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Check {
+    Live,
+    Ready,
+}
+
+pub async fn healthcheck(args: Args, config: &Config) -> Result<()> {
+    let check = match args.check {
+        Check::Live => "live",
+        Check::Ready => "ready",
+    };
+
+    let path = format!("/health/{}", check);
+
+    let (status, body) = server_request(config, Method::GET, &path, None).await?;
+    println!("{}", body);
+    if !status.is_success() {
+        return Err(eyre!(
+            "{}{} returned {}",
+            server_addr(config)?,
+            path,
+            status
+        ));
+    }
+    Ok(())
+}
@@ -1,44 +1,81 @@
-use crate::ws::SignalWebSocket;
-use std::env::{self, Args};
+use crate::{
+    cli::read_password,
+    config::Config,
+    ws::{Debounce, PushPolicy, PushResult, SignalWebSocket},
+};
+use clap::ValueEnum;
+use eyre::{eyre, Result};
+use futures_channel::mpsc;
+use futures_util::{select, FutureExt, StreamExt};
+use std::{sync::Arc, time::Duration};
 
-fn usage() {
-    println!(
-        "
-Usage: {} oneshot wss://signal.server.tld/path https://push.server.ltd/id
+#[derive(clap::Args)]
+pub struct Args {
+    uuid: String,
+    device_id: u32,
+    /// Push endpoint, such as https://push.server.ltd/id
+    endpoint: String,
+    /// When an envelope triggers a push
+    #[arg(long, value_enum, default_value_t = Strategy::Window)]
+    strategy: Strategy,
+    /// Seconds of the window or of the quiet period
+    #[arg(long, default_value_t = 5)]
+    debounce: u64,
+    /// Push at the end of the window or of the quiet period, if envelopes were dropped
+    #[arg(long)]
+    trailing: bool,
+    /// Exit after the first push, with an error if the push server didn't accept it
+    #[arg(long)]
+    exit_after_push: bool,
+}
 
-Strategies:
-  rest        Send all messages
-  websocket   Send all messages at least 5 seconds apart
-",
-        env::args().nth(0).unwrap()
-    );
+#[derive(Clone, Copy, ValueEnum)]
+enum Strategy {
+    /// Push for every envelope
+    Every,
+    /// Push at most once per debounce window
+    Window,
+    /// Push for the first envelope after a quiet period
+    Quiet,
 }
 
-pub async fn oneshot(args: Args) {
-    let argv: Vec<String> = args.collect();
-    if argv.iter().any(|arg| arg == "--help" || arg == "-h") {
-        usage();
-        return;
-    }
-    let connect_addr = match argv.get(0) {
-        Some(argv1) => argv1,
-        None => {
-            usage();
-            return;
+impl Args {
+    fn push_policy(&self) -> PushPolicy {
+        let duration = Duration::from_secs(self.debounce);
+        let debounce = match self.strategy {
+            Strategy::Every => Debounce::Every,
+            Strategy::Window => Debounce::Window(duration),
+            Strategy::Quiet => Debounce::Quiet(duration),
+        };
+        PushPolicy {
+            debounce,
+            trailing: self.trailing,
         }
     }
-    .clone();
-    let push_endpoint = match argv.get(1) {
-        Some(argv2) => argv2,
-        None => {
-            usage();
-            return;
-        }
+}
+
+pub async fn oneshot(args: Args, config: Arc<Config>) -> Result<()> {
+    let password = read_password()?;
+    let mut socket = SignalWebSocket::new(
+        Arc::clone(&config),
+        &args.uuid,
+        args.device_id,
+        &password,
+        args.endpoint.clone(),
+    )?;
+    socket.set_push_policy(args.push_policy());
+    if !args.exit_after_push {
+        return socket.connection_loop().await;
     }
-    .clone();
 
-    let _ = SignalWebSocket::new(connect_addr, push_endpoint)
-        .unwrap()
-        .connection_loop()
-        .await;
+    let (on_push_tx, mut on_push_rx) = mpsc::unbounded::<PushResult>();
+    socket.channels.on_push_tx = Some(on_push_tx);
+    select!(
+        res = socket.connection_loop().fuse() => res,
+        push = on_push_rx.next().fuse() => match push.and_then(|push| push.status) {
+            Some(status) if (200..300).contains(&status) => Ok(()),
+            Some(status) => Err(eyre!("The push server answered {}", status)),
+            None => Err(eyre!("The notification could not be sent")),
+        },
+    )
 }
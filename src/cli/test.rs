@@ -1,98 +1,172 @@
-use crate::{db::MollySocketDb, CONFIG};
-use std::env::{self, Args};
+use crate::{
+    cli::{print_json, Output},
+    config::Config,
+    db::MollySocketDb,
+    server::ConnectionState,
+    utils::test_push::{self, TestPush},
+};
+use clap::Subcommand;
+use eyre::Result;
+use serde::Serialize;
 
-fn usage() {
-    println!(
-        "
-Usage:
-{} test endpoint https://push.server.ltd/id
-{} test uuid aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa
-",
-        env::args().nth(0).unwrap(),
-        env::args().nth(0).unwrap(),
-    );
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check an endpoint is allowed, such as https://push.server.ltd/id
+    #[command(alias = "e")]
+    Endpoint { endpoint: String },
+    /// Check a UUID is allowed and the state of its connection
+    #[command(alias = "u")]
+    Uuid { uuid: String },
+    /// Send a test notification to an endpoint
+    #[command(alias = "p")]
+    Push { endpoint: String },
 }
 
-pub async fn test(args: Args) {
-    let argv: Vec<String> = args.collect();
-    if argv.iter().any(|arg| arg == "--help" || arg == "-h") {
-        usage();
-        return;
-    }
-    let arg = match argv.get(1) {
-        Some(arg) => arg,
-        None => {
-            usage();
-            return;
-        }
-    }
-    .clone();
-    match argv.get(0) {
-        Some(cmd) if cmd == "endpoint" || cmd == "e" => test_endpoint(&arg).await,
-        Some(cmd) if cmd == "uuid" || cmd == "u" => test_uuid(&arg),
-        _ => {
-            usage();
-            return;
-        }
+#[derive(Serialize)]
+struct EndpointReport<'a> {
+    config_file: String,
+    endpoint: &'a str,
+    valid: bool,
+    allowed_endpoints: &'a [String],
+}
+
+#[derive(Serialize)]
+struct UuidReport<'a> {
+    config_file: String,
+    uuid: &'a str,
+    valid: bool,
+    registered: bool,
+    /// The persisted state of the connection, None if it can be started.
+    state: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct PushReport<'a> {
+    config_file: String,
+    endpoint: &'a str,
+    valid: bool,
+    /// `accepted`, `unreachable` or `rejected`, None if the endpoint isn't valid.
+    result: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+/// Returns whether the check succeeded.
+pub async fn test(command: Command, config: &Config, output: Output) -> Result<bool> {
+    match command {
+        Command::Endpoint { endpoint } => test_endpoint(config, &endpoint, output).await,
+        Command::Uuid { uuid } => test_uuid(config, &uuid, output),
+        Command::Push { endpoint } => test_push(config, &endpoint, output).await,
     }
 }
 
-fn print_cfg() {
-    let file = match env::var_os("MOLLY_CONF") {
-        Some(path) => path.into_string().unwrap_or("Error".to_string()),
-        None => "Default".to_string(),
+fn test_uuid(config: &Config, uuid: &str, output: Output) -> Result<bool> {
+    let co = MollySocketDb::new(&config.user_cfg.db)?.get(uuid).ok();
+    let report = UuidReport {
+        config_file: config.file_name(),
+        uuid,
+        valid: config.is_uuid_valid(uuid),
+        registered: co.is_some(),
+        state: co
+            .as_ref()
+            .and_then(ConnectionState::stored)
+            .map(|state| state.as_str()),
     };
-    println!("Config file: {}", file);
-}
-fn test_uuid(uuid: &str) {
-    print_cfg();
-    if !CONFIG.is_uuid_valid(uuid) {
+    let ok = report.valid && report.registered && report.state.is_none();
+    if output == Output::Json {
+        print_json(&report)?;
+        return Ok(ok);
+    }
+
+    println!("Config file: {}", report.config_file);
+    if report.valid {
+        println!("UUID {} is valid", uuid);
+    } else {
         println!("UUID {} is not valid", uuid);
+    }
+    if !report.registered {
+        println!("  No connection is registered with this UUID.");
+    } else if let Some(state) = report.state {
+        println!(
+            "  The connection associated to this UUID is {}.",
+            state.replace('_', " ")
+        );
     } else {
-        println!("UUID {} is valid", uuid);
+        println!("  A connection is associated to this UUID and is ok.");
     }
+    Ok(ok)
+}
 
-    let db = match MollySocketDb::new() {
-        Ok(db) => db,
-        Err(_) => {
-            println!("  An error occured while opening the DB.");
-            return;
-        }
-    };
-    let co = match db.get(uuid) {
-        Ok(co) => co,
-        Err(_) => {
-            println!("  No connection is registered with this UUID.");
-            return;
-        }
+async fn test_endpoint(config: &Config, endpoint: &str, output: Output) -> Result<bool> {
+    let report = EndpointReport {
+        config_file: config.file_name(),
+        endpoint,
+        valid: config.is_endpoint_valid(endpoint).await,
+        allowed_endpoints: &config.user_cfg.allowed_endpoints,
     };
-    if co.forbidden {
-        println!("  The connection associated to this UUID is forbidden.");
-        return;
+    if output == Output::Json {
+        print_json(&report)?;
+        return Ok(report.valid);
     }
-    println!("  A connection is associated to this UUID and is ok.");
-}
 
-async fn test_endpoint(endpoint: &str) {
-    print_cfg();
-    if CONFIG.is_endpoint_valid(endpoint).await {
+    println!("Config file: {}", report.config_file);
+    if report.valid {
         println!("Endpoint {} is valid", endpoint);
     } else {
         println!("Endpoint {} is not valid", endpoint);
-        if CONFIG
-            .user_cfg
-            .allowed_endpoints
-            .contains(&String::from("*"))
-        {
+        if report.allowed_endpoints.contains(&String::from("*")) {
             println!("  The endpoint does not resolve to a global IP.")
         }
         println!("  Below the allowed endpoints:");
-        CONFIG
-            .user_cfg
-            .allowed_endpoints
-            .iter()
-            .for_each(|endpoint| {
-                println!("    '{}'", endpoint);
-            })
+        report.allowed_endpoints.iter().for_each(|endpoint| {
+            println!("    '{}'", endpoint);
+        })
+    }
+    Ok(report.valid)
+}
+
+async fn test_push(config: &Config, endpoint: &str, output: Output) -> Result<bool> {
+    let mut report = PushReport {
+        config_file: config.file_name(),
+        endpoint,
+        valid: config.is_endpoint_valid(endpoint).await,
+        result: None,
+        id: None,
+        status: None,
+    };
+    if report.valid {
+        match test_push::test_push(config, endpoint).await {
+            TestPush::Accepted(id) => {
+                report.result = Some("accepted");
+                report.id = Some(id);
+            }
+            TestPush::Unreachable => report.result = Some("unreachable"),
+            TestPush::Rejected(status) => {
+                report.result = Some("rejected");
+                report.status = Some(status);
+            }
+        }
+    }
+    let ok = report.result == Some("accepted");
+    if output == Output::Json {
+        print_json(&report)?;
+        return Ok(ok);
+    }
+
+    println!("Config file: {}", report.config_file);
+    match (report.result, report.id, report.status) {
+        (None, ..) => println!("Endpoint {} is not valid", endpoint),
+        (_, Some(id), _) => {
+            println!("A test notification has been sent to {}", endpoint);
+            println!("  Its id is {}.", id);
+        }
+        (_, _, Some(status)) => {
+            println!("Endpoint {} rejected the test notification", endpoint);
+            println!("  The push server answered with the status {}.", status);
+        }
+        _ => println!("Endpoint {} is unreachable", endpoint),
     }
+    Ok(ok)
 }
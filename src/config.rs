@@ -1,7 +1,16 @@
-use std::{default::Default, env, fmt::Debug};
-use user_config::{Environment, UserConfig};
+use eyre::{eyre, Result};
+use std::{fmt::Debug, path::PathBuf};
+use trust_dns_resolver::TokioAsyncResolver;
+pub use user_config::{Environment, LogFormat, UserConfig};
 
-use crate::utils::post_allowed::ResolveAllowed;
+use crate::utils::{
+    post_allowed::ResolveAllowed,
+    proxy::{Proxy, ProxyKind},
+    uuid_hash::uuid_hash,
+};
+
+/// Password of the SOCKS credentials isolating the Tor circuits, only the username differs.
+const TOR_ISOLATION_PASSWORD: &str = "mollysocket";
 
 mod user_config;
 
@@ -9,36 +18,87 @@ mod user_config;
 pub struct Config {
     pub version: String,
     pub user_cfg: UserConfig,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Config::load(Some(UserConfig::default()))
-    }
-}
-
-fn pdebug_conf() {
-    let file = match env::var_os("MOLLY_CONF") {
-        Some(path) => path.into_string().unwrap_or("Error".to_string()),
-        None => "Default".to_string(),
-    };
-    log::debug!("Config file: {}", file);
+    pub resolver: TokioAsyncResolver,
+    /// The config file, None for the default location.
+    pub file: Option<PathBuf>,
 }
 
 impl Config {
-    pub fn load(opt_user_cfg: Option<UserConfig>) -> Config {
-        pdebug_conf();
+    pub fn load(opt_user_cfg: Option<UserConfig>) -> Result<Config> {
         let user_cfg = if let Some(cfg) = opt_user_cfg {
             cfg
         } else {
-            UserConfig::load().unwrap()
+            UserConfig::load(None)?
         };
-        Config {
-            version: String::from(option_env!("CARGO_PKG_VERSION").unwrap_or_else(|| "Unknown")),
+        if user_cfg
+            .tor_proxy
+            .as_ref()
+            .is_some_and(|proxy| proxy.kind() == ProxyKind::Http)
+        {
+            return Err(eyre!("tor_proxy must be a SOCKS5 proxy"));
+        }
+        Ok(Config {
+            version: String::from(option_env!("CARGO_PKG_VERSION").unwrap_or("Unknown")),
             user_cfg,
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+            file: None,
+        })
+    }
+
+    /// Load the config from `file`, or from the default location.
+    pub fn load_file(file: Option<PathBuf>) -> Result<Config> {
+        let mut config = Config::load(Some(UserConfig::load(file.as_deref())?))?;
+        config.file = file;
+        Ok(config)
+    }
+
+    pub fn file_name(&self) -> String {
+        match &self.file {
+            Some(path) => path.display().to_string(),
+            None => "Default".to_string(),
+        }
+    }
+
+    /// The proxy of the websockets to Signal server, before the credentials
+    /// of each connection are set, see [Config::signal_proxy].
+    pub fn shared_signal_proxy(&self) -> Option<&Proxy> {
+        self.user_cfg
+            .tor_proxy
+            .as_ref()
+            .or(self.user_cfg.signal_proxy.as_ref())
+            .or(self.user_cfg.proxy.as_ref())
+    }
+
+    /// The proxy of the websocket of `uuid`. Through Tor, each UUID gets its own
+    /// SOCKS credentials: Tor isolates their streams on distinct circuits, so
+    /// Signal server doesn't see the accounts coming from the same exit node.
+    pub fn signal_proxy(&self, uuid: &str) -> Option<Proxy> {
+        let proxy = self.shared_signal_proxy()?;
+        if self.user_cfg.tor_proxy.is_some() {
+            return Some(proxy.with_credentials(&uuid_hash(uuid), TOR_ISOLATION_PASSWORD));
+        }
+        Some(proxy.clone())
+    }
+
+    /// See [UserConfig::registrations_per_minute_per_ip].
+    pub fn registrations_per_minute_per_ip(&self) -> u32 {
+        match (
+            self.user_cfg.registrations_per_minute_per_ip,
+            &self.user_cfg.trusted_ip_header,
+        ) {
+            (Some(limit), _) => limit,
+            (None, Some(_)) => 10,
+            (None, None) => 0,
         }
     }
 
+    pub fn push_proxy(&self) -> Option<&Proxy> {
+        self.user_cfg
+            .push_proxy
+            .as_ref()
+            .or(self.user_cfg.proxy.as_ref())
+    }
+
     pub fn is_uuid_valid(&self, uuid: &str) -> bool {
         self.user_cfg
             .allowed_uuids
@@ -56,7 +116,12 @@ impl Config {
     pub async fn is_url_endpoint_valid(&self, url: &url::Url) -> bool {
         self.is_endpoint_allowed_by_user(url)
             || (self.user_cfg.allowed_endpoints.contains(&String::from("*"))
-                && url.resolve_allowed().await.unwrap_or(vec![]).len().gt(&0))
+                && url
+                    .resolve_allowed(&self.resolver)
+                    .await
+                    .unwrap_or_default()
+                    .len()
+                    .gt(&0))
     }
 
     pub fn is_endpoint_allowed_by_user(&self, url: &url::Url) -> bool {
@@ -93,13 +158,11 @@ mod tests {
     use super::*;
 
     fn test_config(uuid: &str) -> Config {
-        Config {
-            user_cfg: UserConfig {
-                allowed_uuids: vec![String::from(uuid)],
-                ..Default::default()
-            },
+        Config::load(Some(UserConfig {
+            allowed_uuids: vec![String::from(uuid)],
             ..Default::default()
-        }
+        }))
+        .unwrap()
     }
 
     #[test]
@@ -115,6 +178,49 @@ mod tests {
         assert!(!cfg.is_uuid_valid("11111111-3d88-43de-bcdb-f6657d3484e4"));
     }
 
+    #[test]
+    fn per_ip_limit() {
+        let limit = |per_ip: Option<u32>, header: Option<&str>| {
+            Config::load(Some(UserConfig {
+                registrations_per_minute_per_ip: per_ip,
+                trusted_ip_header: header.map(String::from),
+                ..Default::default()
+            }))
+            .unwrap()
+            .registrations_per_minute_per_ip()
+        };
+        // Behind a reverse proxy, the peer address is the same for every client
+        assert_eq!(limit(None, None), 0);
+        assert_eq!(limit(None, Some("X-Real-IP")), 10);
+        assert_eq!(limit(Some(5), None), 5);
+    }
+
+    #[test]
+    fn tor_isolation() {
+        let cfg = Config::load(Some(UserConfig {
+            proxy: Some("http://proxy.lan:3128".parse().unwrap()),
+            tor_proxy: Some("socks5h://127.0.0.1:9050".parse().unwrap()),
+            ..Default::default()
+        }))
+        .unwrap();
+        let first = cfg.signal_proxy("0d2ff653-3d88-43de-bcdb-f6657d3484e4");
+        let second = cfg.signal_proxy("11111111-3d88-43de-bcdb-f6657d3484e4");
+        assert_eq!(first.as_ref().map(Proxy::kind), Some(ProxyKind::Socks5h));
+        assert_ne!(first, second);
+        assert_eq!(
+            first,
+            cfg.signal_proxy("0d2ff653-3d88-43de-bcdb-f6657d3484e4")
+        );
+        // The pushes don't go through Tor
+        assert_eq!(cfg.push_proxy().map(Proxy::kind), Some(ProxyKind::Http));
+
+        let http_tor = Config::load(Some(UserConfig {
+            tor_proxy: Some("http://127.0.0.1:9050".parse().unwrap()),
+            ..Default::default()
+        }));
+        assert!(http_tor.is_err());
+    }
+
     #[tokio::test]
     async fn check_endpoint() {
         let cfg = test_config("*");
@@ -1,5 +1,6 @@
+use crate::{utils::proxy::Proxy, ws::PushPolicy};
 use serde::{Deserialize, Serialize};
-use std::{default::Default, env, fmt::Debug};
+use std::{default::Default, fmt::Debug, path::Path};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Environment {
@@ -7,12 +8,79 @@ pub enum Environment {
     Prod,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per event.
+    Json,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct UserConfig {
     pub environment: Environment,
     pub allowed_endpoints: Vec<String>,
     pub allowed_uuids: Vec<String>,
     pub db: String,
+    /// Seconds given to websockets and in-flight pushes to finish on SIGTERM/SIGINT.
+    /// Keep it below `TimeoutStopSec` when running with systemd.
+    pub shutdown_grace_period: u64,
+    /// Where the webserver listens: `ip:port` or `unix:/path/to.sock`.
+    /// Uses ROCKET_ADDRESS and ROCKET_PORT if not set.
+    pub listen: Option<String>,
+    /// Where the health and metrics routes are served, with the same format as `listen`.
+    /// They are served with the registration API if not set.
+    pub admin_listen: Option<String>,
+    /// Permissions of the unix sockets.
+    pub unix_socket_mode: u32,
+    /// Maximum number of connections exposing their state in the metrics, 0 to disable.
+    pub per_connection_metrics_limit: usize,
+    pub log_format: LogFormat,
+    /// Bearer token of the admin routes, they are not served if not set.
+    pub admin_token: Option<String>,
+    /// Send a test notification to the endpoint before accepting a registration. Off by
+    /// default, until the clients expect it.
+    pub test_push_on_registration: bool,
+    /// Registrations accepted per minute from an address, 0 to disable. Defaults to 10
+    /// with `trusted_ip_header`, else to 0: behind a reverse proxy, every client would
+    /// have the address of the proxy.
+    pub registrations_per_minute_per_ip: Option<u32>,
+    /// Registrations accepted per minute from all addresses, 0 to disable.
+    pub registrations_per_minute: u32,
+    /// Header holding the client address, set by the reverse proxy, such as `X-Real-IP`.
+    /// The peer address is used if not set: the clients could choose their address.
+    pub trusted_ip_header: Option<String>,
+    /// Maximum number of stored connections, 0 to disable.
+    pub max_connections: usize,
+    /// Maximum number of stored connections pushing to the same host, 0 to disable.
+    pub max_connections_per_host: usize,
+    /// Open a websocket to Signal server with the credentials before accepting a registration.
+    pub check_credentials_on_registration: bool,
+    /// Seconds given to Signal server to accept the credentials.
+    pub check_credentials_timeout: u64,
+    /// Consecutive pushes answered 404 or 410 before the endpoint is considered gone.
+    pub endpoint_gone_pushes: u32,
+    /// Seconds the push server must keep answering 404 or 410 before the endpoint
+    /// is considered gone.
+    pub endpoint_gone_after: u64,
+    /// When the envelopes trigger a push, for the connections without their own policy.
+    pub push_policy: PushPolicy,
+    /// Serve the control socket `<db>.sock`, for the CLI to apply its changes to the server.
+    pub control_socket: bool,
+    /// Seconds between two keepalives sent to Signal server.
+    pub keepalive_interval: u64,
+    /// Seconds given to Signal server to answer a keepalive, else the websocket is reopened.
+    pub keepalive_timeout: u64,
+    /// Proxy of the outgoing connections: `http://`, `socks5://` or `socks5h://host:port`.
+    pub proxy: Option<Proxy>,
+    /// Proxy of the websockets to Signal server, overrides `proxy`.
+    pub signal_proxy: Option<Proxy>,
+    /// Proxy of the requests to the push endpoints, overrides `proxy`.
+    pub push_proxy: Option<Proxy>,
+    /// SOCKS5 proxy of Tor, the websockets to Signal server go through it with
+    /// a circuit per UUID. Overrides `signal_proxy`.
+    pub tor_proxy: Option<Proxy>,
 }
 
 impl Default for UserConfig {
@@ -22,12 +90,38 @@ impl Default for UserConfig {
             allowed_endpoints: vec![String::from("http://0.0.0.0/")],
             allowed_uuids: vec![String::from("*")],
             db: String::from("./mollysocket.db"),
+            shutdown_grace_period: 4,
+            listen: None,
+            admin_listen: None,
+            unix_socket_mode: 0o660,
+            per_connection_metrics_limit: 0,
+            log_format: LogFormat::Text,
+            admin_token: None,
+            test_push_on_registration: false,
+            registrations_per_minute_per_ip: None,
+            registrations_per_minute: 60,
+            trusted_ip_header: None,
+            max_connections: 0,
+            max_connections_per_host: 0,
+            check_credentials_on_registration: false,
+            check_credentials_timeout: 10,
+            endpoint_gone_pushes: 3,
+            endpoint_gone_after: 600,
+            push_policy: PushPolicy::default(),
+            control_socket: true,
+            keepalive_interval: 30,
+            keepalive_timeout: 10,
+            proxy: None,
+            signal_proxy: None,
+            push_proxy: None,
+            tor_proxy: None,
         }
     }
 }
 impl UserConfig {
-    pub fn load() -> Result<UserConfig, confy::ConfyError> {
-        let cfg: UserConfig = if let Some(path) = env::var_os("MOLLY_CONF") {
+    /// Load the config from `path`, or from the default location.
+    pub fn load(path: Option<&Path>) -> Result<UserConfig, confy::ConfyError> {
+        let cfg: UserConfig = if let Some(path) = path {
             confy::load_path(path)?
         } else {
             confy::load("mollysocket", None)?
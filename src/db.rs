@@ -1,11 +1,12 @@
 use eyre::Result;
-use rusqlite::{self, Row};
+use rusqlite::{self, params, Row};
 use std::{
     sync::{Arc, Mutex},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::CONFIG;
+use crate::ws::PushPolicy;
+use migrations::Migration;
 
 mod migrations;
 
@@ -21,6 +22,12 @@ pub struct Connection {
     pub endpoint: String,
     pub forbidden: bool,
     pub last_registration: OptTime,
+    /// The push endpoint answered it doesn't exist anymore.
+    pub endpoint_gone: bool,
+    /// Disabled by the administrator: not started until it is enabled.
+    pub disabled: bool,
+    /// When the envelopes trigger a push, the one of the config if None.
+    pub push_policy: Option<PushPolicy>,
 }
 
 #[derive(Debug)]
@@ -41,7 +48,7 @@ impl From<&OptTime> for u64 {
 
 impl From<u64> for OptTime {
     fn from(i: u64) -> OptTime {
-        if i <= 0 {
+        if i == 0 {
             return OptTime(None);
         }
         let duration = Duration::from_secs(i);
@@ -64,13 +71,19 @@ impl Connection {
             endpoint: row.get(3)?,
             forbidden: row.get(4)?,
             last_registration: OptTime::from(row.get::<usize, u64>(5)?),
+            endpoint_gone: row.get(6)?,
+            disabled: row.get(7)?,
+            push_policy: row
+                .get::<usize, Option<String>>(8)?
+                .map(|policy| policy.parse())
+                .transpose()?,
         })
     }
 }
 
 impl MollySocketDb {
-    pub fn new() -> Result<MollySocketDb> {
-        let db = rusqlite::Connection::open(CONFIG.user_cfg.db.clone())?;
+    pub fn new(path: &str) -> Result<MollySocketDb> {
+        let db = rusqlite::Connection::open(path)?;
         db.execute_batch(
             "
 CREATE TABLE IF NOT EXISTS connections(
@@ -83,6 +96,7 @@ CREATE TABLE IF NOT EXISTS connections(
 )
             ",
         )?;
+        db.migrate()?;
         Ok(MollySocketDb {
             db: Arc::new(Mutex::new(db)),
         })
@@ -90,21 +104,20 @@ CREATE TABLE IF NOT EXISTS connections(
 
     pub fn add(&self, co: &Connection) -> Result<()> {
         self.db.lock().unwrap().execute(
-            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
-            VALUES (?, ?, ?, ?, ?, ?);",
-            [&co.uuid, &co.device_id.to_string(), &co.password, &co.endpoint, &String::from(if co.forbidden { "1" } else { "0" }), &u64::from(&co.last_registration).to_string()]
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration, endpoint_gone, disabled, push_policy)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            params![&co.uuid, &co.device_id.to_string(), &co.password, &co.endpoint, &bool_param(co.forbidden), &u64::from(&co.last_registration).to_string(), &bool_param(co.endpoint_gone), &bool_param(co.disabled), co.push_policy.map(|policy| policy.to_string())]
         )?;
         Ok(())
     }
 
     pub fn list(&self) -> Result<Vec<Connection>> {
-        Ok(self
-            .db
+        self.db
             .lock()
             .unwrap()
             .prepare("SELECT * FROM connections;")?
             .query_and_then([], Connection::map)?
-            .collect::<Result<Vec<Connection>>>()?)
+            .collect::<Result<Vec<Connection>>>()
     }
 
     pub fn get(&self, uuid: &str) -> Result<Connection> {
@@ -117,6 +130,15 @@ CREATE TABLE IF NOT EXISTS connections(
             .ok_or(rusqlite::Error::QueryReturnedNoRows)?
     }
 
+    /// Check the database is accessible.
+    pub fn ping(&self) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .query_row("SELECT count(*) FROM connections;", [], |_| Ok(()))?;
+        Ok(())
+    }
+
     pub fn rm(&self, uuid: &str) -> Result<()> {
         self.db
             .lock()
@@ -126,13 +148,17 @@ CREATE TABLE IF NOT EXISTS connections(
     }
 }
 
+fn bool_param(value: bool) -> String {
+    String::from(if value { "1" } else { "0" })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_db() {
-        let db = MollySocketDb::new().unwrap();
+        let db = MollySocketDb::new(":memory:").unwrap();
         let uuid = "0d2ff653-3d88-43de-bcdb-f6657d3484e4";
         db.add(&Connection {
             uuid: String::from(uuid),
@@ -141,14 +167,20 @@ mod tests {
             endpoint: String::from("http://0.0.0.0/"),
             forbidden: false,
             last_registration: OptTime(None),
+            endpoint_gone: false,
+            disabled: true,
+            push_policy: Some("quiet:10:trailing".parse().unwrap()),
         })
         .unwrap();
+        let co = db.get(uuid).unwrap();
+        assert!(co.disabled);
+        assert_eq!(co.push_policy.unwrap().to_string(), "quiet:10:trailing");
         assert!(db
             .list()
             .unwrap()
             .iter()
             .map(|co| &co.uuid)
             .any(|row_uuid| row_uuid == uuid));
-        db.rm(&uuid).unwrap();
+        db.rm(uuid).unwrap();
     }
 }
@@ -1,6 +1,6 @@
 use eyre::Result;
 
-const CURRENT_VERSION: i32 = 1;
+const CURRENT_VERSION: i32 = 3;
 
 pub trait Migration {
     fn migrate(&self) -> Result<()>;
@@ -8,12 +8,54 @@ pub trait Migration {
 
 impl Migration for rusqlite::Connection {
     fn migrate(&self) -> Result<()> {
-        let _user_version: i32 =
+        let user_version: i32 =
             self.query_row("SELECT user_version FROM pragma_user_version;", [], |row| {
                 row.get(0)
             })?;
 
+        // The version wasn't set before version 2
+        if user_version < 2 {
+            self.execute_batch(
+                "
+ALTER TABLE connections ADD COLUMN endpoint_gone BOOLEAN NOT NULL DEFAULT 0 CHECK (endpoint_gone IN (0, 1));
+ALTER TABLE connections ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT 0 CHECK (disabled IN (0, 1));
+                ",
+            )?;
+        }
+
+        if user_version < 3 {
+            self.execute_batch("ALTER TABLE connections ADD COLUMN push_policy TEXT;")?;
+        }
+
         // Upgrade version
         Ok(self.pragma_update(None, "user_version", CURRENT_VERSION)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_first_version() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "
+CREATE TABLE connections(uuid TEXT, forbidden BOOLEAN NOT NULL);
+INSERT INTO connections(uuid, forbidden) VALUES ('uuid', 1);
+            ",
+        )
+        .unwrap();
+        db.migrate().unwrap();
+        // Already migrated
+        db.migrate().unwrap();
+        let disabled: bool = db
+            .query_row("SELECT disabled FROM connections;", [], |row| row.get(0))
+            .unwrap();
+        assert!(!disabled);
+        let push_policy: Option<String> = db
+            .query_row("SELECT push_policy FROM connections;", [], |row| row.get(0))
+            .unwrap();
+        assert!(push_policy.is_none());
+    }
+}
@@ -1,21 +1,15 @@
-use lazy_static::lazy_static;
-
-use config::Config;
+use eyre::Result;
+use std::process::ExitCode;
 
 mod cli;
 mod config;
 mod db;
 mod server;
+mod telemetry;
 mod utils;
 mod ws;
 
-lazy_static! {
-    #[derive(Debug)]
-    static ref CONFIG: Config = Config::load(None);
-}
-
 #[tokio::main]
-async fn main() {
-    env_logger::init();
-    cli::cli().await;
+async fn main() -> Result<ExitCode> {
+    cli::cli().await
 }
@@ -1,28 +1,91 @@
-use crate::{db::MollySocketDb, server::metrics::Metrics};
-use futures_util::{future::join, pin_mut, select, FutureExt};
-use lazy_static::lazy_static;
-use std::sync::{Arc, Mutex};
-use tokio::signal;
+use crate::config::Config;
+use eyre::Result;
+use futures_util::{future::try_join3, pin_mut, select, FutureExt};
+use state::AppState;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    signal::{
+        self,
+        unix::{self, SignalKind},
+    },
+    time,
+};
 
+mod admin;
 mod connections;
+mod control;
+mod health;
+mod listener;
 mod metrics;
+mod rate_limit;
+mod state;
+mod systemd;
 mod web;
 
-lazy_static! {
-    static ref DB: MollySocketDb = MollySocketDb::new().unwrap();
-    static ref METRICS: Metrics = Metrics::new().unwrap();
-    static ref REFS: Arc<Mutex<Vec<connections::LoopRef>>> = Arc::new(Mutex::new(vec![]));
-    static ref TX: Arc<Mutex<connections::OptSender>> = Arc::new(Mutex::new(None));
-}
+pub use connections::ConnectionState;
+pub use control::{request_list, request_reload};
+pub use listener::Listen;
 
-pub async fn run() {
-    let signal_future = signal::ctrl_c().fuse();
-    let joined_future = join(web::launch().fuse(), connections::run().fuse());
+pub async fn run(config: Arc<Config>) -> Result<()> {
+    let grace_period = Duration::from_secs(config.user_cfg.shutdown_grace_period);
+    if let Some(proxy) = config.shared_signal_proxy() {
+        let tor = if config.user_cfg.tor_proxy.is_some() {
+            "Tor at "
+        } else {
+            ""
+        };
+        log::info!(
+            "Connecting to Signal server through {}{}",
+            tor,
+            proxy.address()
+        );
+    }
+    if let Some(proxy) = config.push_proxy() {
+        log::info!("Sending the pushes through {}", proxy.address());
+    }
+    let state = Arc::new(AppState::new(config)?);
+    let notify_task = tokio::spawn(systemd::notify_loop(Arc::clone(&state)));
+    let signal_future = wait_for_signal().fuse();
+    let joined_future = try_join3(
+        web::launch(Arc::clone(&state)),
+        connections::run(Arc::clone(&state)),
+        control::serve(Arc::clone(&state)),
+    )
+    .fuse();
 
     pin_mut!(signal_future, joined_future);
 
     select!(
-        _ = signal_future => log::info!("SIGINT received"),
-        _ = joined_future => log::warn!("Server stopped"),
-    )
+        res = signal_future => log::info!("{} received, shutting down", res?),
+        res = joined_future => {
+            log::warn!("Server stopped");
+            notify_task.abort();
+            res?;
+            return Ok(());
+        },
+    );
+
+    systemd::notify_stopping();
+    notify_task.abort();
+    state.shutdown();
+    match time::timeout(grace_period, joined_future).await {
+        Ok(res) => {
+            res?;
+            log::info!("Server stopped gracefully");
+        }
+        Err(_) => log::warn!(
+            "Grace period of {}s elapsed, remaining connections are dropped",
+            grace_period.as_secs()
+        ),
+    }
+    Ok(())
+}
+
+/// SIGTERM (systemd, docker) and SIGINT are handled the same way.
+async fn wait_for_signal() -> Result<&'static str> {
+    let mut sigterm = unix::signal(SignalKind::terminate())?;
+    Ok(select!(
+        res = signal::ctrl_c().fuse() => res.map(|_| "SIGINT")?,
+        _ = sigterm.recv().fuse() => "SIGTERM",
+    ))
 }
@@ -0,0 +1,132 @@
+use rocket::{
+    get,
+    http::Status,
+    post,
+    request::{FromRequest, Outcome},
+    routes,
+    serde::{json::Json, Serialize},
+    Request, Route, State,
+};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use super::{
+    connections::{self, ConnectionState},
+    state::AppState,
+};
+use crate::db::OptTime;
+
+/// Request guard: the request has the header `Authorization: Bearer <admin_token>`.
+struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        let token = req
+            .rocket()
+            .state::<Arc<AppState>>()
+            .and_then(|state| state.config.user_cfg.admin_token.as_deref());
+        let bearer = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match (token, bearer) {
+            (Some(token), Some(bearer)) if same_token(token, bearer) => Outcome::Success(Admin),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compares the digests, so the time taken doesn't depend on the common prefix.
+pub fn same_token(token: &str, candidate: &str) -> bool {
+    Sha256::digest(token.as_bytes()) == Sha256::digest(candidate.as_bytes())
+}
+
+#[derive(Serialize)]
+pub struct ConnectionStatus {
+    uuid: String,
+    state: &'static str,
+    /// When the next connection attempt is planned, in seconds since the epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<u64>,
+}
+
+impl ConnectionStatus {
+    fn new(uuid: String, co_state: ConnectionState) -> Self {
+        Self {
+            uuid,
+            state: co_state.as_str(),
+            until: co_state
+                .until()
+                .map(|until| u64::from(&OptTime::from(until))),
+        }
+    }
+}
+
+/// The state of the connections tracked by the supervisor, sorted by UUID.
+pub fn statuses(state: &AppState) -> Vec<ConnectionStatus> {
+    let mut connections = state.registry.list();
+    connections.sort_by(|a, b| a.0.cmp(&b.0));
+    connections
+        .into_iter()
+        .map(|(uuid, co_state)| ConnectionStatus::new(uuid, co_state))
+        .collect()
+}
+
+#[get("/connections")]
+fn list(_admin: Admin, state: &State<Arc<AppState>>) -> Json<Vec<ConnectionStatus>> {
+    Json(statuses(state))
+}
+
+#[get("/connections/<uuid>")]
+fn get(_admin: Admin, state: &State<Arc<AppState>>, uuid: &str) -> Option<Json<ConnectionStatus>> {
+    let co_state = match state.registry.get(uuid) {
+        Some(co_state) => co_state,
+        // Stored after the start, by the CLI
+        None => connections::current_state(state, &state.db.get(uuid).ok()?),
+    };
+    Some(Json(ConnectionStatus::new(uuid.to_string(), co_state)))
+}
+
+/// Apply the changes made to the stored connection, by the CLI for instance.
+#[post("/connections/<uuid>/reload")]
+async fn reload(_admin: Admin, state: &State<Arc<AppState>>, uuid: &str) -> Status {
+    match connections::reload(state, uuid).await {
+        Ok(()) => Status::Accepted,
+        Err(e) => {
+            tracing::warn!(event = "reload_error", error = %e, "Could not reload the connection");
+            Status::ServiceUnavailable
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list, get, reload]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn compare_tokens() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secret", "secre"));
+        assert!(!same_token("secret", ""));
+    }
+
+    #[test]
+    fn backing_off_until() {
+        let status = ConnectionStatus::new(
+            String::from("uuid"),
+            ConnectionState::BackingOff {
+                until: UNIX_EPOCH + Duration::from_secs(60),
+            },
+        );
+        assert_eq!(status.state, "backing_off");
+        assert_eq!(status.until, Some(60));
+    }
+}
@@ -1,14 +1,32 @@
 use crate::{
     db::Connection,
-    server::{DB, METRICS, REFS, TX},
-    ws::SignalWebSocket,
-    CONFIG,
+    server::{state::AppState, systemd},
+    utils::{test_push::is_endpoint_gone, uuid_hash::uuid_hash},
+    ws::{PushResult, Reconnection, SignalWebSocket},
 };
-use eyre::Result;
+use eyre::{eyre, Result};
 use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use futures_util::{future::join_all, join, select, Future, FutureExt, StreamExt};
+use futures_util::{
+    future::{self, join_all},
+    join, select, Future, FutureExt, StreamExt,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time;
 use tokio_tungstenite::tungstenite;
 
+mod connection_state;
+mod registry;
+
+pub use connection_state::ConnectionState;
+pub use registry::Registry;
+
+const SUPERVISOR_TICK: Duration = Duration::from_secs(5);
+/// Forget the stopped connections removed from the DB every this number of ticks.
+const RECONCILE_TICKS: u32 = 12;
+
 pub struct LoopRef {
     uuid: String,
     tx: UnboundedSender<bool>,
@@ -16,125 +34,391 @@ pub struct LoopRef {
 
 pub type OptSender = Option<UnboundedSender<Connection>>;
 
-pub async fn run() {
-    let mut connections = DB.list().unwrap();
+pub async fn run(state: Arc<AppState>) -> Result<()> {
+    let mut connections = state.db.list()?;
+    for co in &connections {
+        set_pending(&state, co);
+    }
     let loops: Vec<_> = connections
         .iter_mut()
-        .map(|co| connection_loop(co).fuse())
+        .map(|co| connection_loop(&state, co).fuse())
         .collect();
 
     let (new_connections_tx, new_connections_rx) = mpsc::unbounded();
-    {
-        let mut s_tx = TX.lock().unwrap();
+    if !state.is_shutting_down() {
+        let mut s_tx = state.tx.lock().unwrap();
         *s_tx = Some(new_connections_tx);
     }
 
-    let new_loops = gen_new_loops(new_connections_rx).fuse();
+    let new_loops = gen_new_loops(&state, new_connections_rx).fuse();
+
+    // Polled last: when it first ticks, every stored connection has been started
+    let heartbeat = supervisor_heartbeat(&state).fuse();
+
+    join!(join_all(loops), new_loops, heartbeat);
+    Ok(())
+}
+
+/// Proves the supervisor is still polled, it runs in the same task as the connection loops.
+async fn supervisor_heartbeat(state: &AppState) {
+    state.tick_supervisor();
+    state.set_connections_ready();
+    systemd::notify_ready(state);
+
+    let mut shutdown_rx = state.subscribe_shutdown();
+    let mut ticks = 0;
+    loop {
+        select!(
+            _ = time::sleep(SUPERVISOR_TICK).fuse() => state.tick_supervisor(),
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown).fuse() => break,
+        );
+        ticks += 1;
+        if ticks % RECONCILE_TICKS == 0 {
+            reconcile(state);
+        }
+    }
+}
+
+/// Forget the forbidden connections, or with their endpoint gone, which are no longer in the DB.
+fn reconcile(state: &AppState) {
+    let uuids: Vec<String> = match state.db.list() {
+        Ok(connections) => connections.into_iter().map(|co| co.uuid).collect(),
+        Err(e) => {
+            tracing::warn!(event = "db_error", error = %e, "Could not list the connections");
+            return;
+        }
+    };
+    let removed = state
+        .registry
+        .retain_stopped(|uuid| uuids.iter().any(|stored| stored == uuid));
+    for uuid in removed {
+        state.metrics.remove_connection_state(&uuid_hash(&uuid));
+    }
+    refresh_gauges(state);
+}
+
+/// Update the state of a connection owned by the loop `loop_id`, and the metrics.
+fn set_state(state: &AppState, uuid: &str, loop_id: Option<u64>, co_state: ConnectionState) {
+    if state.registry.set(uuid, loop_id, co_state) {
+        state
+            .metrics
+            .set_connection_state(&uuid_hash(uuid), co_state);
+        refresh_gauges(state);
+    }
+}
+
+/// The state of a stored connection, as tracked by the supervisor or as persisted.
+pub fn current_state(state: &AppState, co: &Connection) -> ConnectionState {
+    state
+        .registry
+        .get(&co.uuid)
+        .or_else(|| ConnectionState::stored(co))
+        .unwrap_or(ConnectionState::Pending)
+}
+
+/// The connection is about to be started, if it isn't in a persisted state.
+fn set_pending(state: &AppState, co: &Connection) {
+    if ConnectionState::stored(co).is_none() && state.registry.set_pending(&co.uuid) {
+        state
+            .metrics
+            .set_connection_state(&uuid_hash(&co.uuid), ConnectionState::Pending);
+        refresh_gauges(state);
+    }
+}
+
+fn refresh_gauges(state: &AppState) {
+    state.metrics.set_gauges(&state.registry.counts());
+}
+
+/// Apply the changes made to a stored connection outside of the server: its loop
+/// is restarted with the stored connection, or killed if the connection is removed.
+pub async fn reload(state: &AppState, uuid: &str) -> Result<()> {
+    match state.db.get(uuid) {
+        Ok(co) => match &*state.tx.lock().unwrap() {
+            Some(tx) => tx.unbounded_send(co)?,
+            None => return Err(eyre!("The connections are not handled")),
+        },
+        Err(e) if is_not_found(&e) => {
+            kill(state, uuid).await;
+            // If it isn't running, else its loop removes it
+            for uuid in state.registry.retain_stopped(|stored| stored != uuid) {
+                state.metrics.remove_connection_state(&uuid_hash(&uuid));
+            }
+            refresh_gauges(state);
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
 
-    join!(join_all(loops), new_loops);
+fn is_not_found(e: &eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<rusqlite::Error>(),
+        Some(rusqlite::Error::QueryReturnedNoRows)
+    )
 }
 
-pub async fn gen_new_loops(rx: UnboundedReceiver<Connection>) {
+pub async fn gen_new_loops(state: &AppState, rx: UnboundedReceiver<Connection>) {
     rx.for_each_concurrent(None, |mut co| async move {
-        kill(&co.uuid).await;
-        connection_loop(&mut co).await;
+        set_pending(state, &co);
+        kill(state, &co.uuid).await;
+        connection_loop(state, &mut co).await;
     })
     .await;
 }
 
-async fn connection_loop(co: &mut Connection) {
-    if co.forbidden {
-        log::info!("Ignoring connection for {}", &co.uuid);
-        METRICS.forbiddens.inc();
+#[tracing::instrument(name = "connection", skip_all, fields(connection = %uuid_hash(&co.uuid)))]
+async fn connection_loop(state: &AppState, co: &mut Connection) {
+    if let Some(stored) = ConnectionState::stored(co) {
+        tracing::info!(
+            event = "connection_ignored",
+            state = stored.as_str(),
+            "Ignoring {} connection",
+            stored.as_str()
+        );
+        // Its previous loop, if any, has been killed
+        state.registry.stop(&co.uuid, stored);
+        state
+            .metrics
+            .set_connection_state(&uuid_hash(&co.uuid), stored);
+        refresh_gauges(state);
         return;
     }
-    log::info!("Starting connection for {}", &co.uuid);
+    tracing::info!(event = "connection_started", "Starting connection");
     let mut socket = match SignalWebSocket::new(
-        CONFIG.get_ws_endpoint(&co.uuid, co.device_id, &co.password),
+        Arc::clone(&state.config),
+        &co.uuid,
+        co.device_id,
+        &co.password,
         co.endpoint.clone(),
     ) {
         Ok(s) => s,
         Err(e) => {
-            log::info!("An error occured for {}: {}", co.uuid, e);
+            tracing::info!(event = "connection_error", error = %e, "An error occured");
             return;
         }
     };
-    let metrics_future = set_metrics(&mut socket);
+    socket.channels.shutdown_rx = Some(state.subscribe_shutdown());
+    socket.set_push_policy(co.push_policy.unwrap_or(state.config.user_cfg.push_policy));
+    let loop_id = state.registry.new_loop_id();
+    let established = Mutex::new(None);
+    let metrics_future = set_metrics(state, &mut socket, &co.uuid, loop_id, &established);
     // Add the channel to kill the connection if needed
     let (kill_tx, mut kill_rx) = mpsc::unbounded();
     {
-        REFS.lock().unwrap().push(LoopRef {
+        state.refs.lock().unwrap().push(LoopRef {
             uuid: co.uuid.clone(),
             tx: kill_tx,
         });
     }
-    METRICS.connections.inc();
+    state.registry.start(&co.uuid, loop_id);
+    set_state(state, &co.uuid, Some(loop_id), ConnectionState::Connecting);
     // loop
+    let mut endpoint_gone = false;
     select!(
-        res = socket.connection_loop().fuse() => handle_connection_closed(res, co),
-        _ = kill_rx.next().fuse() => log::info!("Connection killed"),
-        _ = metrics_future.fuse() => log::warn!("One of the metrics channel has been closed."),
+        res = socket.connection_loop().fuse() => handle_connection_closed(state, res, co),
+        _ = kill_rx.next().fuse() => tracing::info!(event = "connection_killed", "Connection killed"),
+        gone = metrics_future.fuse() => {
+            if gone {
+                tracing::info!(event = "endpoint_gone", "Endpoint gone: stopping the connection");
+                endpoint_gone = true;
+            } else {
+                tracing::warn!(event = "channel_closed", "One of the metrics channel has been closed.");
+            }
+        },
     );
     // Remove the channel to kill the connection
-    let mut refs = REFS.lock().unwrap();
+    let mut refs = state.refs.lock().unwrap();
     if let Some(i_ref) = refs.iter().position(|l_ref| l_ref.uuid.eq(&co.uuid)) {
         refs.remove(i_ref);
     }
-    METRICS.connections.dec();
+    if let Some(since) = established.lock().unwrap().take() {
+        state.metrics.observe_connection_duration(since.elapsed());
+    }
+    if co.forbidden {
+        set_state(state, &co.uuid, Some(loop_id), ConnectionState::Forbidden);
+    } else if endpoint_gone {
+        co.endpoint_gone = true;
+        let _ = state.db.add(co);
+        set_state(
+            state,
+            &co.uuid,
+            Some(loop_id),
+            ConnectionState::EndpointGone,
+        );
+    } else if state.registry.remove(&co.uuid, Some(loop_id)) {
+        state.metrics.remove_connection_state(&uuid_hash(&co.uuid));
+        refresh_gauges(state);
+    }
+}
+
+/// Follows the pushes answered 404 or 410: the endpoint is gone once it answered
+/// so to `min_pushes` consecutive pushes, over at least `min_period`.
+struct GoneEndpoint {
+    min_pushes: u32,
+    min_period: Duration,
+    count: u32,
+    since: Option<Instant>,
 }
 
-fn set_metrics(socket: &mut SignalWebSocket) -> impl Future<Output = ()> {
+impl GoneEndpoint {
+    fn new(min_pushes: u32, min_period: Duration) -> Self {
+        Self {
+            min_pushes,
+            min_period,
+            count: 0,
+            since: None,
+        }
+    }
+
+    /// Returns true if the endpoint is gone. An accepted push resets the count,
+    /// the other failures are ignored.
+    fn on_push(&mut self, status: Option<u16>, now: Instant) -> bool {
+        match status {
+            Some(status) if is_endpoint_gone(status) => {
+                self.count += 1;
+                let since = *self.since.get_or_insert(now);
+                self.count >= self.min_pushes
+                    && now.saturating_duration_since(since) >= self.min_period
+            }
+            Some(status) if (200..300).contains(&status) => {
+                self.count = 0;
+                self.since = None;
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `established` holds since when the websocket is open.
+/// The returned future resolves to true if the push endpoint is gone.
+fn set_metrics<'a>(
+    state: &'a AppState,
+    socket: &mut SignalWebSocket,
+    uuid: &'a str,
+    loop_id: u64,
+    established: &'a Mutex<Option<Instant>>,
+) -> impl Future<Output = bool> + 'a {
     let (on_message_tx, on_message_rx) = mpsc::unbounded::<u32>();
-    let (on_push_tx, on_push_rx) = mpsc::unbounded::<u32>();
-    let (on_reconnection_tx, on_reconnection_rx) = mpsc::unbounded::<u32>();
+    let (on_push_tx, on_push_rx) = mpsc::unbounded::<PushResult>();
+    let (on_keepalive_tx, on_keepalive_rx) = mpsc::unbounded::<Duration>();
+    let (on_reconnection_tx, on_reconnection_rx) = mpsc::unbounded::<Reconnection>();
+    let (on_connection_tx, on_connection_rx) = mpsc::unbounded::<bool>();
     socket.channels.on_message_tx = Some(on_message_tx);
     socket.channels.on_push_tx = Some(on_push_tx);
+    socket.channels.on_keepalive_tx = Some(on_keepalive_tx);
     socket.channels.on_reconnection_tx = Some(on_reconnection_tx);
+    socket.channels.on_connection_tx = Some(on_connection_tx);
+    let mut gone_endpoint = GoneEndpoint::new(
+        state.config.user_cfg.endpoint_gone_pushes,
+        Duration::from_secs(state.config.user_cfg.endpoint_gone_after),
+    );
     async move {
         select!(
             _ = on_message_rx
                 .for_each(|_| async {
-                    METRICS.messages.inc();
+                    state.metrics.messages.inc();
+                    state.registry.record_message(uuid);
                 })
-                .fuse() => (),
-            _ = on_push_rx
-                .for_each(|_| async {
-                    METRICS.pushs.inc();
+                .fuse() => false,
+            gone = on_push_rx
+                .any(|push| {
+                    state.metrics.observe_push(&push);
+                    state.registry.record_push(uuid, push.status);
+                    future::ready(gone_endpoint.on_push(push.status, Instant::now()))
                 })
-                .fuse() => (),
+                .fuse() => gone,
+            _ = on_keepalive_rx
+                .for_each(|rtt| async move { state.metrics.observe_keepalive_rtt(rtt) })
+                .fuse() => false,
             _ = on_reconnection_rx
-                .for_each(|_| async {
-                    METRICS.reconnections.inc();
+                .for_each(|reconnection| async move {
+                    state.metrics.observe_reconnection(reconnection.cause);
+                    let until = SystemTime::now() + reconnection.delay;
+                    set_state(state, uuid, Some(loop_id), ConnectionState::BackingOff { until });
+                })
+                .fuse() => false,
+            _ = on_connection_rx
+                .for_each(|connected| async move {
+                    let mut since = established.lock().unwrap();
+                    match (connected, since.as_ref()) {
+                        (true, None) => *since = Some(Instant::now()),
+                        (false, Some(instant)) => {
+                            state.metrics.observe_connection_duration(instant.elapsed());
+                            *since = None;
+                        }
+                        _ => (),
+                    }
+                    let co_state = if connected {
+                        ConnectionState::Connected
+                    } else {
+                        ConnectionState::Connecting
+                    };
+                    set_state(state, uuid, Some(loop_id), co_state);
                 })
-                .fuse() => (),
+                .fuse() => false,
         )
     }
 }
 
-fn handle_connection_closed(res: Result<()>, co: &mut Connection) {
-    log::debug!("Connection closed.");
+fn handle_connection_closed(state: &AppState, res: Result<()>, co: &mut Connection) {
+    tracing::debug!(event = "connection_closed", "Connection closed.");
 
     match res {
         Ok(()) => (),
         Err(error) => {
-            if let Some(http_error) = error.downcast_ref::<tungstenite::Error>() {
-                if let tungstenite::Error::Http(resp) = http_error {
-                    let status = resp.status();
-                    log::info!("Connection for {} closed with status: {}", &co.uuid, status);
-                    if status == 403 {
-                        co.forbidden = true;
-                        let _ = DB.add(co);
-                        METRICS.forbiddens.inc()
-                    }
+            if let Some(tungstenite::Error::Http(resp)) = error.downcast_ref::<tungstenite::Error>()
+            {
+                let status = resp.status();
+                tracing::info!(
+                    event = "connection_refused",
+                    status = status.as_u16(),
+                    "Connection closed with status: {}",
+                    status
+                );
+                if status == 403 {
+                    co.forbidden = true;
+                    let _ = state.db.add(co);
                 }
             }
         }
     }
 }
 
-async fn kill(uuid: &str) {
-    let refs = REFS.lock().unwrap();
+async fn kill(state: &AppState, uuid: &str) {
+    let refs = state.refs.lock().unwrap();
     if let Some(l_ref) = refs.iter().find(|&l_ref| l_ref.uuid.eq(uuid)) {
         let _ = l_ref.tx.clone().unbounded_send(true);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_gone_after_repeated_failures() {
+        let mut gone = GoneEndpoint::new(3, Duration::from_secs(600));
+        let now = Instant::now();
+        let at = |secs: u64| now + Duration::from_secs(secs);
+        assert!(!gone.on_push(Some(404), at(0)));
+        assert!(!gone.on_push(Some(410), at(10)));
+        // Enough failures, but not for long enough
+        assert!(!gone.on_push(Some(404), at(20)));
+        // The other failures don't count
+        assert!(!gone.on_push(Some(500), at(300)));
+        assert!(!gone.on_push(None, at(400)));
+        assert!(gone.on_push(Some(404), at(600)));
+    }
+
+    #[test]
+    fn accepted_push_resets() {
+        let mut gone = GoneEndpoint::new(2, Duration::ZERO);
+        let now = Instant::now();
+        assert!(!gone.on_push(Some(404), now));
+        assert!(!gone.on_push(Some(201), now));
+        assert!(!gone.on_push(Some(404), now));
+        assert!(gone.on_push(Some(404), now));
+    }
+}
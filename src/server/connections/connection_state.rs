@@ -0,0 +1,122 @@
+use std::time::SystemTime;
+
+use crate::db::Connection;
+
+/// State of a connection, tracked by the supervisor.
+///
+/// A stored connection is `Pending` until its loop starts, then `Connecting`.
+/// The loop moves between `Connecting`, `Connected` and `BackingOff` until it stops.
+/// `Forbidden`, `EndpointGone` and `Disabled` are persisted: no loop is started
+/// until the connection is registered again, or enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Pending,
+    Connecting,
+    Connected,
+    /// Waiting before reconnecting to Signal server.
+    BackingOff {
+        until: SystemTime,
+    },
+    Forbidden,
+    EndpointGone,
+    Disabled,
+}
+
+impl ConnectionState {
+    pub const LABELS: [&'static str; 7] = [
+        "pending",
+        "connecting",
+        "connected",
+        "backing_off",
+        "forbidden",
+        "endpoint_gone",
+        "disabled",
+    ];
+
+    /// The persisted state of a stored connection, if any.
+    pub fn stored(co: &Connection) -> Option<ConnectionState> {
+        if co.disabled {
+            Some(ConnectionState::Disabled)
+        } else if co.forbidden {
+            Some(ConnectionState::Forbidden)
+        } else if co.endpoint_gone {
+            Some(ConnectionState::EndpointGone)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Pending => "pending",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::BackingOff { .. } => "backing_off",
+            ConnectionState::Forbidden => "forbidden",
+            ConnectionState::EndpointGone => "endpoint_gone",
+            ConnectionState::Disabled => "disabled",
+        }
+    }
+
+    /// A connection loop is running, or about to, for this connection.
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Pending
+                | ConnectionState::Connecting
+                | ConnectionState::Connected
+                | ConnectionState::BackingOff { .. }
+        )
+    }
+
+    /// When the next connection attempt is planned.
+    pub fn until(&self) -> Option<SystemTime> {
+        match self {
+            ConnectionState::BackingOff { until } => Some(*until),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OptTime;
+
+    #[test]
+    fn disabled_first() {
+        let mut co = Connection {
+            uuid: String::from("uuid"),
+            device_id: 1,
+            password: String::from("pass"),
+            endpoint: String::from("http://0.0.0.0/"),
+            forbidden: true,
+            last_registration: OptTime(None),
+            endpoint_gone: false,
+            disabled: false,
+            push_policy: None,
+        };
+        assert_eq!(
+            ConnectionState::stored(&co),
+            Some(ConnectionState::Forbidden)
+        );
+        co.disabled = true;
+        assert_eq!(
+            ConnectionState::stored(&co),
+            Some(ConnectionState::Disabled)
+        );
+        co.disabled = false;
+        co.forbidden = false;
+        assert_eq!(ConnectionState::stored(&co), None);
+    }
+
+    #[test]
+    fn labels() {
+        assert!(ConnectionState::LABELS.contains(
+            &ConnectionState::BackingOff {
+                until: SystemTime::now()
+            }
+            .as_str()
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use super::ConnectionState;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+/// What happened lately on a connection, kept when its loop is restarted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Activity {
+    /// Last envelope received from Signal server.
+    pub last_message: Option<SystemTime>,
+    pub last_push: Option<SystemTime>,
+    /// HTTP status of the last push, None if the request failed.
+    pub last_push_status: Option<u16>,
+}
+
+struct Entry {
+    /// The loop owning this entry, None if no loop has been started.
+    loop_id: Option<u64>,
+    state: ConnectionState,
+    activity: Activity,
+}
+
+impl Entry {
+    fn new(loop_id: Option<u64>, state: ConnectionState) -> Self {
+        Self {
+            loop_id,
+            state,
+            activity: Activity::default(),
+        }
+    }
+}
+
+/// State of every known connection, the connection gauges are computed from it.
+pub struct Registry {
+    entries: Mutex<HashMap<String, Entry>>,
+    next_loop_id: AtomicU64,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            next_loop_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn new_loop_id(&self) -> u64 {
+        self.next_loop_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// A new loop takes over the connection.
+    pub fn start(&self, uuid: &str, loop_id: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry(uuid.to_string())
+            .or_insert_with(|| Entry::new(None, ConnectionState::Connecting));
+        entry.loop_id = Some(loop_id);
+        entry.state = ConnectionState::Connecting;
+    }
+
+    /// A stored connection is about to be started, if no loop is running for it.
+    pub fn set_pending(&self, uuid: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(uuid) {
+            Some(entry) if entry.state.is_running() => false,
+            Some(entry) => {
+                entry.loop_id = None;
+                entry.state = ConnectionState::Pending;
+                true
+            }
+            None => {
+                entries.insert(uuid.to_string(), Entry::new(None, ConnectionState::Pending));
+                true
+            }
+        }
+    }
+
+    /// Set the state of a connection. Returns false, and does nothing,
+    /// if another loop is running for the connection.
+    pub fn set(&self, uuid: &str, loop_id: Option<u64>, state: ConnectionState) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(uuid) {
+            Some(entry) if entry.loop_id != loop_id && entry.state.is_running() => false,
+            Some(entry) => {
+                entry.loop_id = loop_id;
+                entry.state = state;
+                true
+            }
+            None => {
+                entries.insert(uuid.to_string(), Entry::new(loop_id, state));
+                true
+            }
+        }
+    }
+
+    /// The connection is stopped from outside of its loop, which is being killed:
+    /// the loop doesn't own the connection anymore.
+    pub fn stop(&self, uuid: &str, state: ConnectionState) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry(uuid.to_string())
+            .or_insert_with(|| Entry::new(None, state));
+        entry.loop_id = None;
+        entry.state = state;
+    }
+
+    /// Remove a connection if it is owned by this loop.
+    pub fn remove(&self, uuid: &str, loop_id: Option<u64>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .get(uuid)
+            .is_some_and(|entry| entry.loop_id == loop_id)
+        {
+            entries.remove(uuid);
+            return true;
+        }
+        false
+    }
+
+    /// Remove the connections without running loop, which are not kept by `keep`.
+    /// Returns the removed uuids.
+    pub fn retain_stopped<F>(&self, keep: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut removed = vec![];
+        self.entries.lock().unwrap().retain(|uuid, entry| {
+            if entry.state.is_running() || keep(uuid) {
+                true
+            } else {
+                removed.push(uuid.clone());
+                false
+            }
+        });
+        removed
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<ConnectionState> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .map(|entry| entry.state)
+    }
+
+    /// An envelope has been received for this connection.
+    pub fn record_message(&self, uuid: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(uuid) {
+            entry.activity.last_message = Some(SystemTime::now());
+        }
+    }
+
+    pub fn record_push(&self, uuid: &str, status: Option<u16>) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(uuid) {
+            entry.activity.last_push = Some(SystemTime::now());
+            entry.activity.last_push_status = status;
+        }
+    }
+
+    pub fn activity(&self, uuid: &str) -> Option<Activity> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .map(|entry| entry.activity)
+    }
+
+    pub fn list(&self) -> Vec<(String, ConnectionState)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(uuid, entry)| (uuid.clone(), entry.state))
+            .collect()
+    }
+
+    /// Number of connections for each state label.
+    pub fn counts(&self) -> HashMap<&'static str, i64> {
+        let mut counts = HashMap::new();
+        for entry in self.entries.lock().unwrap().values() {
+            *counts.entry(entry.state.as_str()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_loop_cannot_update() {
+        let registry = Registry::new();
+        let old = registry.new_loop_id();
+        registry.start("uuid", old);
+        let new = registry.new_loop_id();
+        registry.start("uuid", new);
+
+        assert!(!registry.set("uuid", Some(old), ConnectionState::Connected));
+        assert!(!registry.remove("uuid", Some(old)));
+        assert_eq!(registry.get("uuid"), Some(ConnectionState::Connecting));
+        assert!(registry.set("uuid", Some(new), ConnectionState::Forbidden));
+        assert_eq!(registry.counts()["forbidden"], 1);
+    }
+
+    #[test]
+    fn stopped_from_outside() {
+        let registry = Registry::new();
+        let killed = registry.new_loop_id();
+        registry.start("uuid", killed);
+        registry.stop("uuid", ConnectionState::Disabled);
+
+        assert!(!registry.remove("uuid", Some(killed)));
+        assert_eq!(registry.get("uuid"), Some(ConnectionState::Disabled));
+    }
+
+    #[test]
+    fn activity_kept_on_restart() {
+        let registry = Registry::new();
+        registry.start("uuid", registry.new_loop_id());
+        registry.record_push("uuid", Some(200));
+        registry.start("uuid", registry.new_loop_id());
+
+        let activity = registry.activity("uuid").unwrap();
+        assert!(activity.last_push.is_some());
+        assert_eq!(activity.last_push_status, Some(200));
+        assert!(activity.last_message.is_none());
+    }
+
+    #[test]
+    fn retain_stopped_connections() {
+        let registry = Registry::new();
+        registry.set("forbidden", None, ConnectionState::Forbidden);
+        registry.start("running", registry.new_loop_id());
+
+        let removed = registry.retain_stopped(|_| false);
+        assert_eq!(removed, vec![String::from("forbidden")]);
+        assert_eq!(registry.get("running"), Some(ConnectionState::Connecting));
+    }
+}
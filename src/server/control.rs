@@ -0,0 +1,144 @@
+use crate::config::Config;
+use eyre::{eyre, Result};
+use futures_util::{select, FutureExt};
+use std::{fs, path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+use super::{admin, connections, listener::bind_unix, state::AppState};
+
+/// Only the user running the server, who owns the DB, can control it.
+const SOCKET_MODE: u32 = 0o600;
+
+/// A request received on the control socket, one per line.
+#[derive(Debug, PartialEq)]
+enum Request {
+    /// Apply the changes made to the stored connection.
+    Reload(String),
+    /// The state of the connections, as `/admin/connections`.
+    List,
+}
+
+impl Request {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line == "list" {
+            return Some(Request::List);
+        }
+        match line.split_once(' ') {
+            Some(("reload", uuid)) if !uuid.is_empty() => Some(Request::Reload(uuid.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// The control socket is next to the DB, so the CLI finds it with the same config.
+pub fn socket_path(config: &Config) -> PathBuf {
+    PathBuf::from(format!("{}.sock", config.user_cfg.db))
+}
+
+/// Serve the control socket until the shutdown, if it is enabled. The server
+/// keeps running without it if it can't be bound.
+pub async fn serve(state: Arc<AppState>) -> Result<()> {
+    if !state.config.user_cfg.control_socket {
+        return Ok(());
+    }
+    let path = socket_path(&state.config);
+    let listener = match bind_unix(&path, SOCKET_MODE).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::warn!(event = "control_socket_error", error = %e, "Could not bind the control socket");
+            return Ok(());
+        }
+    };
+    tracing::info!(event = "control_socket", path = %path.display(), "Control socket open");
+
+    let mut shutdown_rx = state.subscribe_shutdown();
+    loop {
+        select!(
+            res = listener.accept().fuse() => {
+                match res {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle(Arc::clone(&state), stream));
+                    }
+                    Err(e) => tracing::warn!(event = "control_socket_error", error = %e, "Could not accept connection"),
+                }
+            },
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown).fuse() => break,
+        );
+    }
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+/// Answers `ok`, the JSON list of the connections for `list`, or `error: <reason>`
+/// to each request, on one line.
+async fn handle(state: Arc<AppState>, stream: UnixStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let res = match Request::parse(&line) {
+            Some(Request::Reload(uuid)) => connections::reload(&state, &uuid)
+                .await
+                .map(|()| String::from("ok")),
+            Some(Request::List) => {
+                serde_json::to_string(&admin::statuses(&state)).map_err(eyre::Report::from)
+            }
+            None => Err(eyre!("invalid request")),
+        };
+        let answer = match res {
+            Ok(answer) => format!("{}\n", answer),
+            Err(e) => format!("error: {}\n", e),
+        };
+        if writer.write_all(answer.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Ask the running server to reload a connection. Fails if the server is not running.
+pub async fn request_reload(config: &Config, uuid: &str) -> Result<()> {
+    request(config, &format!("reload {}", uuid)).await?;
+    Ok(())
+}
+
+/// Ask the running server the state of the connections, as the JSON returned by
+/// `/admin/connections`. Fails if the server is not running.
+pub async fn request_list(config: &Config) -> Result<String> {
+    request(config, "list").await
+}
+
+async fn request(config: &Config, request: &str) -> Result<String> {
+    let stream = UnixStream::connect(socket_path(config)).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let answer = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| eyre!("The server closed the control socket"))?;
+    match answer.strip_prefix("error: ") {
+        Some(e) => Err(eyre!("{}", e)),
+        None => Ok(answer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            Request::parse("reload aaaa\n"),
+            Some(Request::Reload(String::from("aaaa")))
+        );
+        assert_eq!(Request::parse("list\n"), Some(Request::List));
+        assert_eq!(Request::parse("reload \n"), None);
+        assert_eq!(Request::parse("restart aaaa"), None);
+    }
+}
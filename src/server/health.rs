@@ -0,0 +1,92 @@
+use rocket::{
+    get,
+    http::Status,
+    response::status,
+    routes,
+    serde::{json::Json, Serialize},
+    Route, State,
+};
+use std::sync::Arc;
+
+use super::state::AppState;
+
+/// Below this fraction of established websockets, we are not ready.
+const MIN_ESTABLISHED_RATIO: f64 = 0.5;
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    db: bool,
+    supervisor: bool,
+    connections: i64,
+    established: i64,
+}
+
+type HealthResponse = status::Custom<Json<Health>>;
+
+#[get("/live")]
+fn live(state: &State<Arc<AppState>>) -> HealthResponse {
+    let health = check(state);
+    let ok = health.db && health.supervisor;
+    respond(health, ok)
+}
+
+#[get("/ready")]
+fn ready(state: &State<Arc<AppState>>) -> HealthResponse {
+    let health = check(state);
+    let ok = health.db
+        && health.supervisor
+        && state.is_ready()
+        && !state.is_shutting_down()
+        && established_ratio(health.connections, health.established) >= MIN_ESTABLISHED_RATIO;
+    respond(health, ok)
+}
+
+fn check(state: &AppState) -> Health {
+    let db = match state.db.ping() {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(event = "health_db_error", error = %e, "Health: database not accessible");
+            false
+        }
+    };
+    Health {
+        status: "ok",
+        db,
+        supervisor: state.is_supervisor_alive(),
+        connections: state.metrics.connections.get(),
+        established: state.metrics.established.get(),
+    }
+}
+
+fn established_ratio(connections: i64, established: i64) -> f64 {
+    if connections <= 0 {
+        return 1.0;
+    }
+    established as f64 / connections as f64
+}
+
+fn respond(mut health: Health, ok: bool) -> HealthResponse {
+    if ok {
+        status::Custom(Status::Ok, Json(health))
+    } else {
+        health.status = "error";
+        status::Custom(Status::ServiceUnavailable, Json(health))
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![live, ready]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio() {
+        assert_eq!(established_ratio(0, 0), 1.0);
+        assert_eq!(established_ratio(4, 1), 0.25);
+        assert!(established_ratio(4, 2) >= MIN_ESTABLISHED_RATIO);
+    }
+}
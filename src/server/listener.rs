@@ -0,0 +1,207 @@
+use eyre::{eyre, Result};
+use futures_util::{select, FutureExt};
+use rocket::{
+    http::{
+        hyper::{self, body::HttpBody, server::conn::Http, service::service_fn},
+        Header, Method,
+    },
+    local::asynchronous::Client,
+    Ignite, Rocket,
+};
+use std::{
+    convert::Infallible,
+    fs::{self, DirBuilder, Permissions},
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::watch,
+};
+
+/// Maximum size of a request body received on a unix socket.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Where a webserver listens: `127.0.0.1:8020`, `[::1]:8020` or `unix:/path/to.sock`.
+#[derive(Debug, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(eyre!("Missing unix socket path in {}", s));
+            }
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        s.parse::<SocketAddr>()
+            .map(Listen::Tcp)
+            .map_err(|_| eyre!("Invalid listen address: {}", s))
+    }
+}
+
+/// Bind a unix socket with the permissions `mode`. A socket left by a previous
+/// instance is replaced, the socket of a running instance is not.
+pub async fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(eyre!("{} is used by a running instance", path.display()));
+        }
+        fs::remove_file(path)?;
+    }
+    // Bound in a private directory, then moved: the socket is never reachable with
+    // the permissions given by the umask
+    let name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid unix socket path: {}", path.display()))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp_path = dir.join(name);
+    let res = UnixListener::bind(&tmp_path)
+        .and_then(|listener| {
+            fs::set_permissions(&tmp_path, Permissions::from_mode(mode))?;
+            fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        })
+        .map_err(eyre::Report::from);
+    let _ = fs::remove_dir_all(&dir);
+    res
+}
+
+/// Serve a rocket instance on a unix socket. Rocket 0.5 only binds TCP sockets, so the
+/// requests are dispatched to a local client, which runs the complete request lifecycle.
+pub async fn serve_unix(
+    rocket: Rocket<Ignite>,
+    path: &Path,
+    mode: u32,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    let listener = bind_unix(path, mode).await?;
+    let client = Arc::new(Client::untracked(rocket).await?);
+    tracing::info!(event = "listening", path = %path.display(), "Listening on unix socket");
+
+    loop {
+        select!(
+            res = listener.accept().fuse() => {
+                let (stream, _) = match res {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!(event = "unix_socket_error", path = %path.display(), error = %e, "Could not accept connection");
+                        continue;
+                    }
+                };
+                let client = Arc::clone(&client);
+                tokio::spawn(async move {
+                    let service = service_fn(|req| dispatch(Arc::clone(&client), req));
+                    if let Err(e) = Http::new().serve_connection(stream, service).await {
+                        tracing::debug!(event = "unix_socket_error", error = %e, "Error while serving unix socket connection");
+                    }
+                });
+            },
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown).fuse() => break,
+        );
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+async fn dispatch(
+    client: Arc<Client>,
+    req: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, Infallible> {
+    let (parts, mut body) = req.into_parts();
+
+    let method = match Method::from_str(parts.method.as_str()) {
+        Ok(method) => method,
+        Err(_) => return Ok(empty_response(405)),
+    };
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if data.len() + chunk.len() <= MAX_BODY_SIZE => data.extend_from_slice(&chunk),
+            Ok(_) => return Ok(empty_response(413)),
+            Err(_) => return Ok(empty_response(400)),
+        }
+    }
+    let uri = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+
+    let mut local_req = client.req(method, uri);
+    for (name, value) in parts.headers.iter() {
+        if let Ok(value) = value.to_str() {
+            local_req.add_header(Header::new(name.as_str().to_string(), value.to_string()));
+        }
+    }
+    local_req.set_body(data);
+
+    let local_resp = local_req.dispatch().await;
+    let mut resp = hyper::Response::builder().status(local_resp.status().code);
+    for header in local_resp.headers().iter() {
+        resp = resp.header(header.name().as_str(), header.value());
+    }
+    let body = local_resp.into_bytes().await.unwrap_or_default();
+    Ok(resp
+        .body(hyper::Body::from(body))
+        .unwrap_or_else(|_| empty_response(500)))
+}
+
+fn empty_response(status: u16) -> hyper::Response<hyper::Body> {
+    let mut resp = hyper::Response::new(hyper::Body::empty());
+    if let Ok(status) = status.try_into() {
+        *resp.status_mut() = status;
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen() {
+        assert_eq!(
+            "127.0.0.1:8020".parse::<Listen>().unwrap(),
+            Listen::Tcp("127.0.0.1:8020".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:8020".parse::<Listen>().unwrap(),
+            Listen::Tcp("[::1]:8020".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/mollysocket/api.sock".parse::<Listen>().unwrap(),
+            Listen::Unix(PathBuf::from("/run/mollysocket/api.sock"))
+        );
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_live_socket() {
+        let path = std::env::temp_dir().join(format!("mollysocket-{}.sock", std::process::id()));
+        let listener = bind_unix(&path, 0o600).await.unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(bind_unix(&path, 0o600).await.is_err());
+        // The socket of a stopped instance is replaced
+        drop(listener);
+        assert!(path.exists());
+        bind_unix(&path, 0o600).await.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,78 +1,273 @@
-use std::fmt::Display;
-
 use eyre::Result;
-use rocket::{http::uri::Origin, Build, Rocket};
 use rocket_prometheus::{
-    prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge},
+    prometheus::{
+        histogram_opts, opts, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+        IntGaugeVec,
+    },
     PrometheusMetrics,
 };
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::connections::ConnectionState;
+use crate::ws::{Disconnection, PushResult};
 
 pub struct Metrics {
     pub connections: IntGauge,
+    pub established: IntGauge,
     pub forbiddens: IntGauge,
-    pub reconnections: IntCounter,
+    pub backing_offs: IntGauge,
+    pub endpoints_gone: IntGauge,
+    pub states: IntGaugeVec,
+    pub reconnections: IntCounterVec,
     pub messages: IntCounter,
-    pub pushs: IntCounter,
+    pub pushs: IntCounterVec,
+    pub push_durations: HistogramVec,
+    pub connection_durations: Histogram,
+    pub keepalive_rtts: Histogram,
+    pub connection_states: IntGaugeVec,
+    /// Maximum number of connections with a per connection state, 0 to disable them.
+    per_connection_limit: usize,
+    tracked_connections: Mutex<HashSet<String>>,
 }
 
 impl Metrics {
-    pub fn new() -> Result<Self> {
-        let connections =
-            register_int_gauge!("mollysocket_connections", "Connections to Signal server")?;
-        let forbiddens = register_int_gauge!(
+    pub fn new(per_connection_limit: usize) -> Result<Self> {
+        let connections = IntGauge::new("mollysocket_connections", "Connections to Signal server")?;
+        let established = IntGauge::new(
+            "mollysocket_established",
+            "Connections to Signal server with an open websocket",
+        )?;
+        let forbiddens = IntGauge::new(
             "mollysocket_forbiddens",
-            "Forbidden connections to Signal server"
+            "Forbidden connections to Signal server",
+        )?;
+        let backing_offs = IntGauge::new(
+            "mollysocket_backing_offs",
+            "Connections waiting before reconnecting to Signal server",
+        )?;
+        let endpoints_gone = IntGauge::new(
+            "mollysocket_endpoints_gone",
+            "Connections stopped because their UnifiedPush endpoint is gone",
+        )?;
+        let states = IntGaugeVec::new(
+            opts!("mollysocket_states", "Connections in each state"),
+            &["state"],
+        )?;
+        let reconnections = IntCounterVec::new(
+            opts!("mollysocket_reconnections", "Reconnections since the start"),
+            &["cause"],
+        )?;
+        let messages = IntCounter::new("mollysocket_messages", "Messages received from Signal")?;
+        let pushs = IntCounterVec::new(
+            opts!(
+                "mollysocket_pushs",
+                "Push messages sent to UnifiedPush endpoint"
+            ),
+            &["host", "status"],
+        )?;
+        let push_durations = HistogramVec::new(
+            histogram_opts!(
+                "mollysocket_push_duration_seconds",
+                "Duration of the requests to UnifiedPush endpoint"
+            ),
+            &["host"],
         )?;
-        let reconnections =
-            register_int_counter!("mollysocket_reconnections", "Reconnections since the start")?;
-        let messages =
-            register_int_counter!("mollysocket_messages", "Messages received from Signal")?;
-        let pushs = register_int_counter!(
-            "mollysocket_pushs",
-            "Push messages sent to UnifiedPush endpoint"
+        let connection_durations = Histogram::with_opts(histogram_opts!(
+            "mollysocket_connection_duration_seconds",
+            "Duration of the websockets to Signal server",
+            vec![1., 10., 60., 300., 900., 3600., 14400., 43200., 86400.]
+        ))?;
+        let keepalive_rtts = Histogram::with_opts(histogram_opts!(
+            "mollysocket_keepalive_rtt_seconds",
+            "Round-trip time of the keepalives sent to Signal server",
+            vec![0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.]
+        ))?;
+        let connection_states = IntGaugeVec::new(
+            opts!(
+                "mollysocket_connection_state",
+                "State of each connection, identified by a hash of its UUID"
+            ),
+            &["connection", "state"],
         )?;
 
         Ok(Self {
             connections,
+            established,
             forbiddens,
+            backing_offs,
+            endpoints_gone,
+            states,
             reconnections,
             messages,
             pushs,
+            push_durations,
+            connection_durations,
+            keepalive_rtts,
+            connection_states,
+            per_connection_limit,
+            tracked_connections: Mutex::new(HashSet::new()),
         })
     }
-}
-
-pub trait MountMetrics {
-    fn mount_metrics<'a, B>(self, base: B, metrics: &Metrics) -> Self
-    where
-        B: TryInto<Origin<'a>> + Clone + Display,
-        B::Error: Display;
-}
 
-impl MountMetrics for Rocket<Build> {
-    fn mount_metrics<'a, B>(self, base: B, metrics: &Metrics) -> Self
-    where
-        B: TryInto<Origin<'a>> + Clone + Display,
-        B::Error: Display,
-    {
+    /// The fairing counting the HTTP requests, and the handler exposing every metric.
+    pub fn prometheus(&self) -> Result<PrometheusMetrics> {
         let prometheus = PrometheusMetrics::new();
         let prom_registry = prometheus.registry();
-        prom_registry
-            .register(Box::new(metrics.connections.clone()))
-            .unwrap();
-        prom_registry
-            .register(Box::new(metrics.forbiddens.clone()))
-            .unwrap();
-        prom_registry
-            .register(Box::new(metrics.reconnections.clone()))
-            .unwrap();
-        prom_registry
-            .register(Box::new(metrics.messages.clone()))
-            .unwrap();
-        prom_registry
-            .register(Box::new(metrics.pushs.clone()))
-            .unwrap();
-
-        self.attach(prometheus.clone()).mount(base, prometheus)
+        prom_registry.register(Box::new(self.connections.clone()))?;
+        prom_registry.register(Box::new(self.established.clone()))?;
+        prom_registry.register(Box::new(self.forbiddens.clone()))?;
+        prom_registry.register(Box::new(self.backing_offs.clone()))?;
+        prom_registry.register(Box::new(self.endpoints_gone.clone()))?;
+        prom_registry.register(Box::new(self.states.clone()))?;
+        prom_registry.register(Box::new(self.reconnections.clone()))?;
+        prom_registry.register(Box::new(self.messages.clone()))?;
+        prom_registry.register(Box::new(self.pushs.clone()))?;
+        prom_registry.register(Box::new(self.push_durations.clone()))?;
+        prom_registry.register(Box::new(self.connection_durations.clone()))?;
+        prom_registry.register(Box::new(self.keepalive_rtts.clone()))?;
+        if self.per_connection_limit > 0 {
+            prom_registry.register(Box::new(self.connection_states.clone()))?;
+        }
+        Ok(prometheus)
+    }
+
+    /// Set the connection gauges from the number of connections for each state label.
+    pub fn set_gauges(&self, counts: &HashMap<&'static str, i64>) {
+        let count = |label| counts.get(label).copied().unwrap_or(0);
+        self.connections
+            .set(count("connecting") + count("connected") + count("backing_off"));
+        self.established.set(count("connected"));
+        self.forbiddens.set(count("forbidden"));
+        self.backing_offs.set(count("backing_off"));
+        self.endpoints_gone.set(count("endpoint_gone"));
+        for label in ConnectionState::LABELS {
+            self.states.with_label_values(&[label]).set(count(label));
+        }
+    }
+
+    pub fn observe_push(&self, push: &PushResult) {
+        let status = match push.status {
+            Some(status) => format!("{}xx", status / 100),
+            None => String::from("error"),
+        };
+        self.pushs.with_label_values(&[&push.host, &status]).inc();
+        self.push_durations
+            .with_label_values(&[&push.host])
+            .observe(push.duration.as_secs_f64());
+    }
+
+    pub fn observe_reconnection(&self, disconnection: Disconnection) {
+        self.reconnections
+            .with_label_values(&[disconnection.as_str()])
+            .inc();
+    }
+
+    pub fn observe_connection_duration(&self, duration: Duration) {
+        self.connection_durations.observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_keepalive_rtt(&self, rtt: Duration) {
+        self.keepalive_rtts.observe(rtt.as_secs_f64());
+    }
+
+    /// Set the state of a connection, if the per connection metrics are enabled
+    /// and the limit isn't reached.
+    pub fn set_connection_state(&self, connection: &str, state: ConnectionState) {
+        if self.per_connection_limit == 0 {
+            return;
+        }
+        let mut tracked = self.tracked_connections.lock().unwrap();
+        if !tracked.contains(connection) {
+            if tracked.len() >= self.per_connection_limit {
+                return;
+            }
+            tracked.insert(connection.to_string());
+        }
+        self.clear_connection_states(connection);
+        self.connection_states
+            .with_label_values(&[connection, state.as_str()])
+            .set(1);
+    }
+
+    pub fn remove_connection_state(&self, connection: &str) {
+        if self.tracked_connections.lock().unwrap().remove(connection) {
+            self.clear_connection_states(connection);
+        }
+    }
+
+    fn clear_connection_states(&self, connection: &str) {
+        for label in ConnectionState::LABELS {
+            let _ = self
+                .connection_states
+                .remove_label_values(&[connection, label]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket_prometheus::prometheus::core::Collector;
+
+    #[test]
+    fn push_status_class() {
+        let metrics = Metrics::new(0).unwrap();
+        metrics.observe_push(&PushResult {
+            host: String::from("push.tld"),
+            status: Some(204),
+            duration: Duration::from_millis(20),
+        });
+        metrics.observe_push(&PushResult {
+            host: String::from("push.tld"),
+            status: None,
+            duration: Duration::from_millis(20),
+        });
+        assert_eq!(
+            metrics.pushs.with_label_values(&["push.tld", "2xx"]).get(),
+            1
+        );
+        assert_eq!(
+            metrics.pushs.with_label_values(&["push.tld", "error"]).get(),
+            1
+        );
+    }
+
+    #[test]
+    fn gauges_from_states() {
+        let metrics = Metrics::new(0).unwrap();
+        metrics.set_gauges(&HashMap::from([
+            ("connected", 2),
+            ("backing_off", 1),
+            ("forbidden", 1),
+        ]));
+        assert_eq!(metrics.connections.get(), 3);
+        assert_eq!(metrics.states.with_label_values(&["disabled"]).get(), 0);
+        assert_eq!(metrics.established.get(), 2);
+        assert_eq!(metrics.forbiddens.get(), 1);
+        assert_eq!(metrics.endpoints_gone.get(), 0);
+    }
+
+    #[test]
+    fn connection_states_limit() {
+        let metrics = Metrics::new(1).unwrap();
+        metrics.set_connection_state("a", ConnectionState::Connecting);
+        metrics.set_connection_state("a", ConnectionState::Connected);
+        metrics.set_connection_state("b", ConnectionState::Connecting);
+        // Only the last state of "a" is exposed, "b" is over the limit
+        assert_eq!(metrics.connection_states.collect()[0].get_metric().len(), 1);
+
+        metrics.remove_connection_state("a");
+        metrics.set_connection_state("b", ConnectionState::Connecting);
+        assert_eq!(
+            metrics
+                .connection_states
+                .with_label_values(&["b", "connecting"])
+                .get(),
+            1
+        );
     }
 }
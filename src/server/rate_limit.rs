@@ -0,0 +1,241 @@
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::state::AppState;
+
+/// Maximum number of tracked addresses. Above, the full buckets are forgotten, and
+/// the new addresses are only limited globally if none is full.
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// The full buckets are looked for at most once per this interval.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Request guard: the address of the client. It is read from `trusted_ip_header`
+/// if set, else it is the peer address.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Infallible> {
+        let header = req
+            .rocket()
+            .state::<Arc<AppState>>()
+            .and_then(|state| state.config.user_cfg.trusted_ip_header.as_deref())
+            .and_then(|name| req.headers().get_one(name));
+        let remote = req.remote().map(|addr| addr.ip());
+        Outcome::Success(ClientIp(client_ip(header, remote)))
+    }
+}
+
+/// The reverse proxy appends the address to the header, the previous ones were sent by the client.
+fn client_ip(header: Option<&str>, remote: Option<IpAddr>) -> Option<IpAddr> {
+    header
+        .and_then(|header| header.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or(remote)
+}
+
+/// Token bucket refilled with `per_minute` tokens per minute, holding at most `per_minute` tokens.
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * per_minute as f64 / 60.0).min(per_minute as f64);
+        self.last = now;
+    }
+
+    fn has_token(&mut self, per_minute: u32, now: Instant) -> bool {
+        self.refill(per_minute, now);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&self, per_minute: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens + elapsed.as_secs_f64() * per_minute as f64 / 60.0 >= per_minute as f64
+    }
+}
+
+/// The buckets of the client addresses.
+#[derive(Default)]
+struct Ips {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_prune: Option<Instant>,
+}
+
+impl Ips {
+    /// The bucket of `ip`, None if too many addresses are tracked.
+    fn bucket(&mut self, ip: IpAddr, per_minute: u32, now: Instant) -> Option<&mut Bucket> {
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= MAX_TRACKED_IPS {
+            let prune = self
+                .last_prune
+                .is_none_or(|last| now.saturating_duration_since(last) >= PRUNE_INTERVAL);
+            if prune {
+                self.buckets
+                    .retain(|_, bucket| !bucket.is_full(per_minute, now));
+                self.last_prune = Some(now);
+            }
+            if self.buckets.len() >= MAX_TRACKED_IPS {
+                return None;
+            }
+        }
+        Some(
+            self.buckets
+                .entry(ip)
+                .or_insert_with(|| Bucket::new(per_minute, now)),
+        )
+    }
+}
+
+/// Limits the registrations per client address and globally, 0 disables a limit.
+pub struct RateLimiter {
+    per_ip: u32,
+    global: u32,
+    ips: Mutex<Ips>,
+    all: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: u32, global: u32) -> Self {
+        Self {
+            per_ip,
+            global,
+            ips: Mutex::new(Ips::default()),
+            all: Mutex::new(Bucket::new(global, Instant::now())),
+        }
+    }
+
+    /// Returns false if the request must be refused.
+    pub fn check(&self, ip: Option<IpAddr>) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    /// A token is taken from the buckets only if both limits accept the request.
+    fn check_at(&self, ip: Option<IpAddr>, now: Instant) -> bool {
+        let mut ips = self.ips.lock().unwrap();
+        let mut all = self.all.lock().unwrap();
+        let mut ip_bucket = match ip {
+            Some(ip) if self.per_ip > 0 => ips.bucket(ip, self.per_ip, now),
+            _ => None,
+        };
+        let ip_allowed = ip_bucket
+            .as_mut()
+            .is_none_or(|bucket| bucket.has_token(self.per_ip, now));
+        let global_allowed = self.global == 0 || all.has_token(self.global, now);
+        if !ip_allowed || !global_allowed {
+            return false;
+        }
+        if let Some(bucket) = ip_bucket {
+            bucket.take();
+        }
+        if self.global > 0 {
+            all.take();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    const OTHER_IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)));
+
+    #[test]
+    fn per_ip() {
+        let limiter = RateLimiter::new(2, 0);
+        let now = Instant::now();
+        assert!(limiter.check_at(IP, now));
+        assert!(limiter.check_at(IP, now));
+        assert!(!limiter.check_at(IP, now));
+        assert!(limiter.check_at(OTHER_IP, now));
+        // One token every 30 seconds
+        assert!(limiter.check_at(IP, now + Duration::from_secs(30)));
+        assert!(!limiter.check_at(IP, now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn global() {
+        let limiter = RateLimiter::new(0, 1);
+        let now = Instant::now();
+        assert!(limiter.check_at(IP, now));
+        assert!(!limiter.check_at(OTHER_IP, now));
+        assert!(!limiter.check_at(None, now));
+    }
+
+    #[test]
+    fn refused_globally_keeps_ip_token() {
+        let limiter = RateLimiter::new(1, 60);
+        let now = Instant::now();
+        for i in 0..60 {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+            assert!(limiter.check_at(Some(ip), now));
+        }
+        assert!(!limiter.check_at(IP, now));
+        // The global bucket gets a token every second, the per-ip one every minute
+        assert!(limiter.check_at(IP, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn tracked_ips_bounded() {
+        let limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+        for i in 0..MAX_TRACKED_IPS as u32 {
+            assert!(limiter.check_at(Some(IpAddr::V4(Ipv4Addr::from(i))), now));
+        }
+        // Not tracked: only limited globally
+        assert!(limiter.check_at(IP, now));
+        assert!(limiter.check_at(IP, now));
+        assert_eq!(limiter.ips.lock().unwrap().buckets.len(), MAX_TRACKED_IPS);
+        // Once the buckets are full again, they are forgotten
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.check_at(IP, later));
+        assert!(!limiter.check_at(IP, later));
+        assert_eq!(limiter.ips.lock().unwrap().buckets.len(), 1);
+    }
+
+    #[test]
+    fn client_address() {
+        let remote = Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(client_ip(None, remote), remote);
+        assert_eq!(client_ip(Some("1.2.3.4"), remote), IP);
+        // The first address was sent by the client
+        assert_eq!(client_ip(Some("5.6.7.8, 1.2.3.4"), remote), IP);
+        assert_eq!(client_ip(Some("unknown"), remote), remote);
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = RateLimiter::new(0, 0);
+        let now = Instant::now();
+        assert!((0..100).all(|_| limiter.check_at(IP, now)));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{config::Config, db::MollySocketDb};
+use eyre::Result;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+use super::{
+    connections::{self, Registry},
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+};
+
+/// The supervisor is considered stalled if it hasn't ticked for this long.
+const SUPERVISOR_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Everything the web routes and the connection loops share.
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub db: MollySocketDb,
+    pub metrics: Metrics,
+    pub registry: Registry,
+    pub rate_limiter: RateLimiter,
+    pub refs: Mutex<Vec<connections::LoopRef>>,
+    pub tx: Mutex<connections::OptSender>,
+    shutdown: watch::Sender<bool>,
+    web_ready: AtomicBool,
+    connections_ready: AtomicBool,
+    supervisor_tick: Mutex<Instant>,
+}
+
+impl AppState {
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        Ok(Self {
+            db: MollySocketDb::new(&config.user_cfg.db)?,
+            metrics: Metrics::new(config.user_cfg.per_connection_metrics_limit)?,
+            registry: Registry::new(),
+            rate_limiter: RateLimiter::new(
+                config.registrations_per_minute_per_ip(),
+                config.user_cfg.registrations_per_minute,
+            ),
+            refs: Mutex::new(vec![]),
+            tx: Mutex::new(None),
+            shutdown: watch::channel(false).0,
+            web_ready: AtomicBool::new(false),
+            connections_ready: AtomicBool::new(false),
+            supervisor_tick: Mutex::new(Instant::now()),
+            config,
+        })
+    }
+
+    /// Ask the web server and every connection loop to stop.
+    /// New connections can't be started anymore.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.tx.lock().unwrap().take();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn set_web_ready(&self) {
+        self.web_ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_connections_ready(&self) {
+        self.connections_ready.store(true, Ordering::SeqCst);
+    }
+
+    /// The web server is listening and the stored connections are started.
+    pub fn is_ready(&self) -> bool {
+        self.web_ready.load(Ordering::SeqCst) && self.connections_ready.load(Ordering::SeqCst)
+    }
+
+    /// Called by the connection supervisor every time it is polled.
+    pub fn tick_supervisor(&self) {
+        *self.supervisor_tick.lock().unwrap() = Instant::now();
+    }
+
+    pub fn is_supervisor_alive(&self) -> bool {
+        self.supervisor_tick.lock().unwrap().elapsed() < SUPERVISOR_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Arc<Config> {
+        Arc::new(
+            Config::load(Some(crate::config::UserConfig {
+                db: String::from(":memory:"),
+                ..Default::default()
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn independent_states() {
+        let config = test_config();
+        let first = AppState::new(Arc::clone(&config)).unwrap();
+        let second = AppState::new(config).unwrap();
+        first.metrics.connections.inc();
+        assert_eq!(second.metrics.connections.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn ready_when_web_and_connections_are() {
+        let state = AppState::new(test_config()).unwrap();
+        state.set_web_ready();
+        assert!(!state.is_ready());
+        state.set_connections_ready();
+        assert!(state.is_ready());
+        assert!(state.is_supervisor_alive());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_new_connections() {
+        let state = AppState::new(test_config()).unwrap();
+        let mut shutdown_rx = state.subscribe_shutdown();
+        *state.tx.lock().unwrap() = Some(futures_channel::mpsc::unbounded().0);
+        assert!(!state.is_shutting_down());
+
+        state.shutdown();
+        assert!(state.is_shutting_down());
+        assert!(state.tx.lock().unwrap().is_none());
+        shutdown_rx.wait_for(|shutdown| *shutdown).await.unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use sd_notify::NotifyState;
+use std::{sync::Arc, time::Duration};
+use tokio::time;
+
+use super::state::AppState;
+
+/// Interval of the STATUS= updates when the watchdog is disabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Nothing is sent if we are not started by systemd with `Type=notify`.
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        log::warn!("Could not notify systemd: {}", e);
+    }
+}
+
+/// Send READY=1 once the web server is listening and the stored connections are started.
+pub fn notify_ready(state: &AppState) {
+    if state.is_ready() {
+        log::debug!("Notifying systemd we are ready");
+        notify(&[NotifyState::Ready, NotifyState::Status(&status(state))]);
+    }
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+fn status(state: &AppState) -> String {
+    format!(
+        "{} connections, {} forbidden",
+        state.metrics.connections.get(),
+        state.metrics.forbiddens.get(),
+    )
+}
+
+fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec))
+    } else {
+        None
+    }
+}
+
+/// Periodically send the STATUS=, and WATCHDOG=1 if the connection supervisor
+/// is still making progress.
+pub async fn notify_loop(state: Arc<AppState>) {
+    let watchdog = watchdog_timeout();
+    let interval = watchdog.map_or(STATUS_INTERVAL, |timeout| timeout / 2);
+    loop {
+        time::sleep(interval).await;
+        let status = status(&state);
+        if watchdog.is_some() && state.is_supervisor_alive() {
+            notify(&[NotifyState::Watchdog, NotifyState::Status(&status)]);
+        } else {
+            if watchdog.is_some() {
+                log::warn!("The connection supervisor is stalled: skipping the watchdog ping");
+            }
+            notify(&[NotifyState::Status(&status)]);
+        }
+    }
+}
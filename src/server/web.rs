@@ -1,15 +1,37 @@
 use crate::{
     db::{Connection, OptTime},
-    CONFIG,
+    utils::{
+        endpoint_host::endpoint_host,
+        test_push::{test_push, TestPush},
+        uuid_hash::uuid_hash,
+    },
+    ws::{self, CheckCredentials},
 };
 use eyre::Result;
+use futures_util::future::try_join;
 use rocket::{
-    get, post, routes,
+    config::Shutdown,
+    fairing::AdHoc,
+    get,
+    http::Status,
+    post, routes,
     serde::{json::Json, Deserialize, Serialize},
+    Build, Rocket, State,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::SystemTime,
 };
-use std::{collections::HashMap, time::SystemTime};
 
-use super::{metrics::MountMetrics, DB, METRICS, TX};
+use super::{
+    admin::{self, same_token},
+    connections, health,
+    listener::{self, Listen},
+    rate_limit::ClientIp,
+    state::AppState,
+    systemd,
+};
 
 #[derive(Serialize)]
 struct Response {
@@ -24,14 +46,27 @@ struct ConnectionData {
     pub endpoint: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    pub uuid: String,
+    pub device_id: u32,
+    pub password: String,
+}
+
 #[derive(Debug)]
 enum RegistrationStatus {
     New,
     Updated,
     Running,
     Forbidden,
+    Disabled,
+    RateLimited,
+    CapacityReached,
     InvalidUuid,
     InvalidEndpoint,
+    EndpointUnreachable,
+    EndpointRejected,
+    SignalUnreachable,
     InternalError,
 }
 
@@ -41,9 +76,14 @@ impl From<RegistrationStatus> for String {
             RegistrationStatus::New | RegistrationStatus::Updated | RegistrationStatus::Running => {
                 "ok"
             }
-            RegistrationStatus::Forbidden => "forbidden",
+            RegistrationStatus::Forbidden | RegistrationStatus::Disabled => "forbidden",
+            RegistrationStatus::RateLimited => "rate_limited",
+            RegistrationStatus::CapacityReached => "capacity_reached",
             RegistrationStatus::InvalidUuid => "invalid_uuid",
             RegistrationStatus::InvalidEndpoint => "invalid_endpoint",
+            RegistrationStatus::EndpointUnreachable => "endpoint_unreachable",
+            RegistrationStatus::EndpointRejected => "endpoint_rejected",
+            RegistrationStatus::SignalUnreachable => "signal_unreachable",
             RegistrationStatus::InternalError => "internal_error",
         }
         .into()
@@ -51,40 +91,64 @@ impl From<RegistrationStatus> for String {
 }
 
 #[get("/")]
-fn discover() -> Json<Response> {
-    gen_rep(HashMap::new())
+fn discover(state: &State<Arc<AppState>>) -> Json<Response> {
+    gen_rep(state, HashMap::new())
 }
 
 #[post("/", format = "application/json", data = "<co_data>")]
-async fn register(co_data: Json<ConnectionData>) -> Json<Response> {
-    let mut status = registration_status(&co_data).await;
+#[tracing::instrument(name = "registration", skip_all, fields(connection = %uuid_hash(&co_data.uuid)))]
+async fn register(
+    state: &State<Arc<AppState>>,
+    co_data: Json<ConnectionData>,
+    client_ip: ClientIp,
+) -> Result<Json<Response>, Status> {
+    if state.is_shutting_down() {
+        tracing::debug!(
+            event = "registration_refused",
+            "Shutting down: registration refused"
+        );
+        return Err(Status::ServiceUnavailable);
+    }
+    let mut map = HashMap::new();
+    let ip = client_ip.0;
+    let mut status = if state.rate_limiter.check(ip) {
+        registration_status(state, &co_data).await
+    } else {
+        tracing::info!(event = "registration_rate_limited", ip = ?ip, "Too many registrations");
+        RegistrationStatus::RateLimited
+    };
     match status {
         RegistrationStatus::Updated | RegistrationStatus::New => {
-            if let Err(_) = new_connection(co_data) {
-                log::debug!("Could not start new connection");
-                status = RegistrationStatus::InternalError;
-            } else {
-                log::debug!("Connection succeeded");
-            }
+            status = start_connection(state, co_data, status, &mut map).await;
         }
         RegistrationStatus::Forbidden => {
-            log::debug!("Connection is currently forbidden");
-            if let Ok(co) = DB.get(&co_data.uuid) {
+            tracing::debug!(
+                event = "registration_forbidden",
+                "Connection is currently forbidden"
+            );
+            if let Ok(co) = state.db.get(&co_data.uuid) {
                 if co.device_id != co_data.device_id || co.password != co_data.password {
-                    if let Ok(_) = new_connection(co_data) {
-                        log::debug!("Connection succeeded");
-                        status = RegistrationStatus::Updated;
-                        METRICS.forbiddens.dec();
-                    } else {
-                        log::debug!("Could not start new connection");
-                        status = RegistrationStatus::InternalError;
-                    }
+                    status =
+                        start_connection(state, co_data, RegistrationStatus::Updated, &mut map)
+                            .await;
                 }
             } else {
-                log::debug!("Could not get info in DB about the connection");
+                tracing::debug!(
+                    event = "registration_error",
+                    "Could not get info in DB about the connection"
+                );
                 status = RegistrationStatus::InternalError;
             }
         }
+        RegistrationStatus::RateLimited
+        | RegistrationStatus::CapacityReached
+        | RegistrationStatus::InternalError => (),
+        RegistrationStatus::Disabled => {
+            tracing::debug!(
+                event = "registration_disabled",
+                "Connection is disabled by the administrator"
+            );
+        }
         RegistrationStatus::Running => {
             //TODO: Update last registration for ::Running
 
@@ -94,18 +158,146 @@ async fn register(co_data: Json<ConnectionData>) -> Json<Response> {
             // If the connection is for an invalid uuid or an error occured : we ignore it
         }
         _ => {
-            log::debug!("Status unknown: {status:?}");
+            tracing::debug!(event = "registration_error", "Status unknown: {status:?}");
             status = RegistrationStatus::InternalError;
         }
     }
-    log::debug!("Status: {status:?}");
-    gen_rep(HashMap::from([(
-        String::from("status"),
-        String::from(status),
-    )]))
+    tracing::debug!(event = "registration", "Status: {status:?}");
+    map.insert(String::from("status"), String::from(status));
+    Ok(gen_rep(state, map))
+}
+
+/// Check Signal server accepts the credentials and the endpoint accepts a test notification,
+/// then store and start the connection. The id of the test notification is added to the response.
+async fn start_connection(
+    state: &AppState,
+    co_data: Json<ConnectionData>,
+    status: RegistrationStatus,
+    map: &mut HashMap<String, String>,
+) -> RegistrationStatus {
+    if state.config.user_cfg.check_credentials_on_registration {
+        if let Some(status) = check_credentials(state, &co_data).await {
+            return status;
+        }
+    }
+    if state.config.user_cfg.test_push_on_registration {
+        match test_push(&state.config, &co_data.endpoint).await {
+            TestPush::Accepted(id) => {
+                map.insert(String::from("test_push_id"), id);
+            }
+            TestPush::Unreachable => {
+                tracing::debug!(
+                    event = "registration_unreachable",
+                    "The endpoint is unreachable"
+                );
+                return RegistrationStatus::EndpointUnreachable;
+            }
+            TestPush::Rejected(push_status) => {
+                tracing::debug!(
+                    event = "registration_rejected",
+                    status = push_status,
+                    "The endpoint rejected the test notification"
+                );
+                return RegistrationStatus::EndpointRejected;
+            }
+        }
+    }
+    match new_connection(state, co_data) {
+        Ok(()) => {
+            tracing::debug!(event = "registration_succeeded", "Connection succeeded");
+            status
+        }
+        Err(e) => {
+            tracing::debug!(event = "registration_error", error = %e, "Could not start new connection");
+            RegistrationStatus::InternalError
+        }
+    }
+}
+
+/// State of the connection, for the account owning it.
+#[post("/status", format = "application/json", data = "<creds>")]
+#[tracing::instrument(name = "status", skip_all, fields(connection = %uuid_hash(&creds.uuid)))]
+fn status(
+    state: &State<Arc<AppState>>,
+    creds: Json<Credentials>,
+    client_ip: ClientIp,
+) -> Result<Json<Response>, Status> {
+    // Charged like the registrations, the password could be guessed otherwise
+    if !state.rate_limiter.check(client_ip.0) {
+        tracing::info!(event = "status_rate_limited", ip = ?client_ip.0, "Too many requests");
+        return Err(Status::TooManyRequests);
+    }
+    let co = match state.db.get(&creds.uuid) {
+        Ok(co) if co.device_id == creds.device_id && same_token(&co.password, &creds.password) => {
+            co
+        }
+        // Unknown connections are not distinguished from wrong credentials
+        _ => {
+            tracing::debug!(
+                event = "status_refused",
+                "Unknown connection or credentials"
+            );
+            return Err(Status::Forbidden);
+        }
+    };
+    let co_state = connections::current_state(state, &co);
+    let activity = state.registry.activity(&co.uuid).unwrap_or_default();
+    let mut map = HashMap::from([
+        (String::from("status"), String::from("ok")),
+        (String::from("state"), String::from(co_state.as_str())),
+    ]);
+    let times = [
+        ("until", co_state.until()),
+        ("last_envelope", activity.last_message),
+        ("last_push", activity.last_push),
+    ];
+    for (key, time) in times {
+        if let Some(time) = time {
+            map.insert(
+                String::from(key),
+                u64::from(&OptTime::from(time)).to_string(),
+            );
+        }
+    }
+    if activity.last_push.is_some() {
+        let push_status = match activity.last_push_status {
+            Some(status) => status.to_string(),
+            None => String::from("error"),
+        };
+        map.insert(String::from("last_push_status"), push_status);
+    }
+    Ok(gen_rep(state, map))
+}
+
+/// Returns the status of the registration if Signal server doesn't accept the credentials.
+async fn check_credentials(
+    state: &AppState,
+    co_data: &ConnectionData,
+) -> Option<RegistrationStatus> {
+    match ws::check_credentials(
+        &state.config,
+        &co_data.uuid,
+        co_data.device_id,
+        &co_data.password,
+    )
+    .await
+    {
+        CheckCredentials::Ok => None,
+        CheckCredentials::Forbidden(_) => {
+            tracing::debug!(
+                event = "registration_forbidden",
+                "Signal server refused the credentials"
+            );
+            Some(RegistrationStatus::Forbidden)
+        }
+        CheckCredentials::Unreachable(e) => {
+            tracing::debug!(event = "registration_unreachable", error = %e, "Could not reach Signal server");
+            Some(RegistrationStatus::SignalUnreachable)
+        }
+    }
 }
 
-fn new_connection(co_data: Json<ConnectionData>) -> Result<()> {
+fn new_connection(state: &AppState, co_data: Json<ConnectionData>) -> Result<()> {
     let co = Connection {
         uuid: co_data.uuid.clone(),
         device_id: co_data.device_id,
@@ -113,17 +305,25 @@ fn new_connection(co_data: Json<ConnectionData>) -> Result<()> {
         endpoint: co_data.endpoint.clone(),
         forbidden: false,
         last_registration: OptTime::from(SystemTime::now()),
+        endpoint_gone: false,
+        disabled: false,
+        // Set with the CLI, kept when the connection is registered again
+        push_policy: state
+            .db
+            .get(&co_data.uuid)
+            .ok()
+            .and_then(|co| co.push_policy),
     };
-    DB.add(&co).unwrap();
-    if let Some(tx) = &*TX.lock().unwrap() {
+    state.db.add(&co)?;
+    if let Some(tx) = &*state.tx.lock().unwrap() {
         let _ = tx.unbounded_send(co);
     }
     Ok(())
 }
 
-async fn registration_status(co_data: &ConnectionData) -> RegistrationStatus {
-    let endpoint_valid = CONFIG.is_endpoint_valid(&co_data.endpoint).await;
-    let uuid_valid = CONFIG.is_uuid_valid(&co_data.uuid);
+async fn registration_status(state: &AppState, co_data: &ConnectionData) -> RegistrationStatus {
+    let endpoint_valid = state.config.is_endpoint_valid(&co_data.endpoint).await;
+    let uuid_valid = state.config.is_uuid_valid(&co_data.uuid);
 
     if !uuid_valid {
         return RegistrationStatus::InvalidUuid;
@@ -133,36 +333,172 @@ async fn registration_status(co_data: &ConnectionData) -> RegistrationStatus {
         return RegistrationStatus::InvalidEndpoint;
     }
 
-    let co = match DB.get(&co_data.uuid) {
+    let co = match state.db.get(&co_data.uuid) {
         Ok(co) => co,
-        Err(_) => {
-            return RegistrationStatus::New;
-        }
+        Err(_) => return capacity_status(state, co_data).unwrap_or(RegistrationStatus::New),
     };
 
-    if co.device_id == co_data.device_id && co.password == co_data.password {
+    if co.endpoint != co_data.endpoint {
+        if let Some(status) = capacity_status(state, co_data) {
+            return status;
+        }
+    }
+    if co.disabled {
+        RegistrationStatus::Disabled
+    } else if co.device_id == co_data.device_id && co.password == co_data.password {
         // Credentials are not updated
         if co.forbidden {
-            return RegistrationStatus::Forbidden;
-        } else if co.endpoint != co_data.endpoint {
-            return RegistrationStatus::Updated;
+            RegistrationStatus::Forbidden
+        } else if co.endpoint != co_data.endpoint || co.endpoint_gone {
+            RegistrationStatus::Updated
         } else {
-            return RegistrationStatus::Running;
+            RegistrationStatus::Running
         }
     } else {
-        return RegistrationStatus::Updated;
+        RegistrationStatus::Updated
     }
 }
 
-fn gen_rep(mut map: HashMap<String, String>) -> Json<Response> {
-    map.insert(String::from("version"), CONFIG.version.clone());
+/// Returns the status of the registration if storing this connection would exceed
+/// `max_connections`, or `max_connections_per_host` for its endpoint.
+fn capacity_status(state: &AppState, co_data: &ConnectionData) -> Option<RegistrationStatus> {
+    let max = state.config.user_cfg.max_connections;
+    let max_per_host = state.config.user_cfg.max_connections_per_host;
+    if max == 0 && max_per_host == 0 {
+        return None;
+    }
+    let connections = match state.db.list() {
+        Ok(connections) => connections,
+        Err(e) => {
+            tracing::warn!(event = "db_error", error = %e, "Could not list the connections");
+            return Some(RegistrationStatus::InternalError);
+        }
+    };
+    let others: Vec<_> = connections
+        .iter()
+        .filter(|co| co.uuid != co_data.uuid)
+        .collect();
+    if max > 0 && others.len() >= max {
+        tracing::info!(
+            event = "capacity_reached",
+            "Maximum number of connections reached"
+        );
+        return Some(RegistrationStatus::CapacityReached);
+    }
+    let host = endpoint_host(&co_data.endpoint);
+    if max_per_host > 0
+        && others
+            .iter()
+            .filter(|co| endpoint_host(&co.endpoint) == host)
+            .count()
+            >= max_per_host
+    {
+        tracing::info!(
+            event = "capacity_reached",
+            host = host.as_deref().unwrap_or(""),
+            "Maximum number of connections for this push server reached"
+        );
+        return Some(RegistrationStatus::CapacityReached);
+    }
+    None
+}
+
+fn gen_rep(state: &AppState, mut map: HashMap<String, String>) -> Json<Response> {
+    map.insert(String::from("version"), state.config.version.clone());
     Json(Response { mollysocket: map })
 }
 
-pub async fn launch() {
-    let _ = rocket::build()
-        .mount("/", routes![discover, register])
-        .mount_metrics("/metrics", &METRICS)
-        .launch()
+pub async fn launch(state: Arc<AppState>) -> Result<()> {
+    let listen = parse_listen(&state.config.user_cfg.listen)?;
+    let admin_listen = parse_listen(&state.config.user_cfg.admin_listen)?;
+    let prometheus = state.metrics.prometheus()?;
+
+    let public = build(&state, &listen)
+        .mount("/", routes![discover, register, status])
+        .attach(prometheus.clone())
+        .attach(AdHoc::on_liftoff("Readiness", |rocket| {
+            Box::pin(async move {
+                if let Some(state) = rocket.state::<Arc<AppState>>() {
+                    state.set_web_ready();
+                    systemd::notify_ready(state);
+                }
+            })
+        }));
+
+    match admin_listen {
+        // Without a dedicated listener, admin routes are served with the public ones
+        None => {
+            let rocket = mount_admin(&state, public)
+                .mount("/health", health::routes())
+                .mount("/metrics", prometheus);
+            serve(&state, rocket, &listen).await
+        }
+        admin_listen => {
+            let admin = mount_admin(&state, build(&state, &admin_listen))
+                .mount("/health", health::routes())
+                .attach(prometheus.clone())
+                .mount("/metrics", prometheus);
+            try_join(
+                serve(&state, public, &listen),
+                serve(&state, admin, &admin_listen),
+            )
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+fn mount_admin(state: &AppState, rocket: Rocket<Build>) -> Rocket<Build> {
+    if state.config.user_cfg.admin_token.is_some() {
+        rocket.mount("/admin", admin::routes())
+    } else {
+        rocket
+    }
+}
+
+fn parse_listen(listen: &Option<String>) -> Result<Option<Listen>> {
+    listen.as_deref().map(str::parse::<Listen>).transpose()
+}
+
+fn build(state: &Arc<AppState>, listen: &Option<Listen>) -> Rocket<Build> {
+    // Signals are handled by the server, which notifies Rocket when to shut down
+    let shutdown = Shutdown {
+        ctrlc: false,
+        signals: HashSet::new(),
+        grace: state.config.user_cfg.shutdown_grace_period as u32,
+        mercy: 0,
+        ..Default::default()
+    };
+    let mut figment = rocket::Config::figment().merge(("shutdown", shutdown));
+    if let Some(Listen::Tcp(addr)) = listen {
+        figment = figment
+            .merge(("address", addr.ip()))
+            .merge(("port", addr.port()));
+    }
+    rocket::custom(figment).manage(Arc::clone(state))
+}
+
+async fn serve(state: &AppState, rocket: Rocket<Build>, listen: &Option<Listen>) -> Result<()> {
+    let rocket = rocket.ignite().await?;
+
+    if let Some(Listen::Unix(path)) = listen {
+        return listener::serve_unix(
+            rocket,
+            path,
+            state.config.user_cfg.unix_socket_mode,
+            state.subscribe_shutdown(),
+        )
         .await;
+    }
+
+    let handle = rocket.shutdown();
+    let mut shutdown_rx = state.subscribe_shutdown();
+    tokio::spawn(async move {
+        if shutdown_rx.wait_for(|shutdown| *shutdown).await.is_ok() {
+            handle.notify();
+        }
+    });
+
+    rocket.launch().await?;
+    Ok(())
 }
@@ -0,0 +1,105 @@
+use crate::config::LogFormat;
+use eyre::Result;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+/// Flushes the pending spans when dropped.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// Log to stderr, filtered with `level` or else `RUST_LOG`. Records of the `log` crate are forwarded.
+///
+/// With the `otel` feature, the spans are also exported with OTLP
+/// if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init(format: LogFormat, level: Option<&str>) -> Result<Telemetry> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::from_default_env(),
+    };
+    let registry = tracing_subscriber::registry().with(fmt_layer(format).with_filter(filter));
+
+    #[cfg(feature = "otel")]
+    {
+        let provider = otel::provider()?;
+        registry
+            .with(provider.as_ref().map(otel::layer))
+            .try_init()?;
+        Ok(Telemetry { provider })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.try_init()?;
+        Ok(Telemetry {})
+    }
+}
+
+/// The JSON lines hold the fields of the event, and the list of its spans
+/// with their fields, such as the hashed uuid of the `connection`.
+fn fmt_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Could not export the last spans: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use eyre::Result;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use std::env;
+    use tracing::Subscriber;
+    use tracing_subscriber::{filter::LevelFilter, registry::LookupSpan, Layer};
+
+    pub fn provider() -> Result<Option<SdkTracerProvider>> {
+        if env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+            return Ok(None);
+        }
+        let exporter = SpanExporter::builder().with_http().build()?;
+        Ok(Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(env!("CARGO_PKG_NAME"))
+                        .build(),
+                )
+                .build(),
+        ))
+    }
+
+    /// Export the spans down to the info level, whatever `RUST_LOG` is.
+    pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+            .with_filter(LevelFilter::INFO)
+    }
+}
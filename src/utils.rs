@@ -1 +1,6 @@
+pub mod endpoint_host;
 pub mod post_allowed;
+pub mod proxy;
+pub mod send_request;
+pub mod test_push;
+pub mod uuid_hash;
@@ -0,0 +1,20 @@
+/// Host of a push endpoint, None if the endpoint is not a valid URL.
+pub fn endpoint_host(endpoint: &str) -> Option<String> {
+    url::Url::parse(endpoint)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host() {
+        assert_eq!(
+            endpoint_host("https://push.server.ltd:8080/id?up"),
+            Some(String::from("push.server.ltd"))
+        );
+        assert_eq!(endpoint_host("push.server.ltd/id"), None);
+    }
+}
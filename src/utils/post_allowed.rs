@@ -1,20 +1,18 @@
 use async_trait::async_trait;
 use eyre::{eyre, Result};
-use lazy_static::lazy_static;
+use hyper::{header, Body};
 use reqwest::redirect::Policy;
 use std::{
     error::Error as StdError,
     fmt::{Display, Formatter},
     net::{IpAddr, SocketAddr},
 };
+use tokio_native_tls::TlsConnector;
 use trust_dns_resolver::{lookup_ip::LookupIp, TokioAsyncResolver};
-use url::{Host, Url};
+use url::{Host, Position, Url};
 
-use crate::CONFIG;
-
-lazy_static! {
-    static ref RESOLVER: TokioAsyncResolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
-}
+use super::{proxy::Proxy, send_request::send_request};
+use crate::config::Config;
 
 #[derive(Debug)]
 enum Error {
@@ -30,7 +28,11 @@ impl Display for Error {
 
 impl StdError for Error {}
 
-pub async fn post_allowed(url: Url, body: &[(&str, &str)]) -> Result<reqwest::Response> {
+pub async fn post_allowed(
+    config: &Config,
+    url: Url,
+    body: &[(&str, &str)],
+) -> Result<reqwest::Response> {
     let port = match url.port() {
         Some(p) => p,
         None if url.scheme() == "http" => 80,
@@ -38,11 +40,13 @@ pub async fn post_allowed(url: Url, body: &[(&str, &str)]) -> Result<reqwest::Re
         _ => return Err(eyre!(Error::SchemeNotAllowed)),
     };
 
-    let client = if CONFIG.is_endpoint_allowed_by_user(&url) {
+    // The endpoints allowed by the user are usually on the local network: they
+    // are never sent through the proxy
+    let builder = if config.is_endpoint_allowed_by_user(&url) {
         reqwest::ClientBuilder::new().redirect(Policy::none())
     } else {
         let resolved_socket_addrs = url
-            .resolve_allowed()
+            .resolve_allowed(&config.resolver)
             .await?
             .into_iter()
             .map(|ip| SocketAddr::new(ip, port))
@@ -51,34 +55,80 @@ pub async fn post_allowed(url: Url, body: &[(&str, &str)]) -> Result<reqwest::Re
         if resolved_socket_addrs.is_empty() {
             log::info!(
                 "Ignoring request to {}: no allowed ip",
-                url.host_str().unwrap_or(&"No host")
+                url.host_str().unwrap_or("No host")
             );
             return Err(eyre!(Error::HostNotAllowed));
         }
 
+        if let Some(proxy) = config.push_proxy() {
+            return post_through_proxy(proxy, &url, &resolved_socket_addrs, body).await;
+        }
         reqwest::ClientBuilder::new()
             .redirect(Policy::none())
             .no_trust_dns()
             .resolve_to_addrs(url.host_str().unwrap(), &resolved_socket_addrs)
-    }
-    .build()
-    .unwrap();
+    };
+    let client = builder.build()?;
 
     Ok(client.post(url).json(&body).send().await?)
 }
 
+/// Post through the proxy, tunneled to the allowed addresses: the proxy doesn't
+/// resolve the host again. TLS is still negotiated with the host of the URL.
+async fn post_through_proxy(
+    proxy: &Proxy,
+    url: &Url,
+    addrs: &[SocketAddr],
+    body: &[(&str, &str)],
+) -> Result<reqwest::Response> {
+    let mut tunnel = Err(eyre!(Error::HostNotAllowed));
+    for addr in addrs {
+        tunnel = proxy.connect_addr(*addr).await;
+        if tunnel.is_ok() {
+            break;
+        }
+    }
+    let stream = tunnel?;
+
+    let req = hyper::Request::post(&url[Position::BeforePath..])
+        .header(
+            header::HOST,
+            &url[Position::BeforeHost..Position::AfterPort],
+        )
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&body)?))?;
+    let resp = if url.scheme() == "https" {
+        // Without the brackets of the IPv6 addresses
+        let domain = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(eyre!(Error::HostNotAllowed)),
+        };
+        let tls = TlsConnector::from(native_tls::TlsConnector::new()?);
+        send_request(tls.connect(&domain, stream).await?, req).await?
+    } else {
+        send_request(stream, req).await?
+    };
+
+    // Redirections are not followed, like with the other clients
+    let (parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    Ok(http::Response::from_parts(parts, body).into())
+}
+
 #[async_trait]
 pub trait ResolveAllowed {
-    async fn resolve_allowed(&self) -> Result<Vec<IpAddr>>;
+    async fn resolve_allowed(&self, resolver: &TokioAsyncResolver) -> Result<Vec<IpAddr>>;
 }
 
 #[async_trait]
 impl ResolveAllowed for Url {
-    async fn resolve_allowed(&self) -> Result<Vec<IpAddr>> {
+    async fn resolve_allowed(&self, resolver: &TokioAsyncResolver) -> Result<Vec<IpAddr>> {
         if ["http", "https"].contains(&self.scheme()) {
             self.host()
                 .ok_or(Error::HostNotAllowed)?
-                .resolve_allowed()
+                .resolve_allowed(resolver)
                 .await
         } else {
             Err(eyre!(Error::SchemeNotAllowed))
@@ -88,18 +138,18 @@ impl ResolveAllowed for Url {
 
 #[async_trait]
 impl ResolveAllowed for Host<&str> {
-    async fn resolve_allowed(&self) -> Result<Vec<IpAddr>> {
+    async fn resolve_allowed(&self, resolver: &TokioAsyncResolver) -> Result<Vec<IpAddr>> {
         match self {
             Host::Domain(d) => {
-                RESOLVER
+                resolver
                     .lookup_ip(*d)
                     .await
                     .map_err(|_| Error::HostNotAllowed)?
-                    .resolve_allowed()
+                    .resolve_allowed(resolver)
                     .await
             }
-            Host::Ipv4(ip) if ip_rfc::global_v4(ip) => Ok(vec![IpAddr::V4(ip.clone())]),
-            Host::Ipv6(ip) if ip_rfc::global_v6(ip) => Ok(vec![IpAddr::V6(ip.clone())]),
+            Host::Ipv4(ip) if ip_rfc::global_v4(ip) => Ok(vec![IpAddr::V4(*ip)]),
+            Host::Ipv6(ip) if ip_rfc::global_v6(ip) => Ok(vec![IpAddr::V6(*ip)]),
             _ => Err(eyre!(Error::HostNotAllowed)),
         }
     }
@@ -107,28 +157,31 @@ impl ResolveAllowed for Host<&str> {
 
 #[async_trait]
 impl ResolveAllowed for LookupIp {
-    async fn resolve_allowed(&self) -> Result<Vec<IpAddr>> {
-        Ok(self.iter().filter(|ip| ip_rfc::global(ip)).collect())
+    async fn resolve_allowed(&self, _resolver: &TokioAsyncResolver) -> Result<Vec<IpAddr>> {
+        Ok(self.iter().filter(ip_rfc::global).collect())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::str::FromStr;
+    use crate::config::UserConfig;
+    use std::{str::FromStr, time::Duration};
 
     async fn len_from_str(url: &str) -> usize {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
         Url::from_str(url)
             .unwrap()
-            .resolve_allowed()
+            .resolve_allowed(&resolver)
             .await
-            .unwrap_or(vec![])
+            .unwrap_or_default()
             .len()
     }
 
     #[tokio::test]
     async fn test_post() {
         post_allowed(
+            &Config::load(Some(Default::default())).unwrap(),
             Url::from_str("https://httpbin.org/post").unwrap(),
             &[("foo", "blah")],
         )
@@ -136,6 +189,110 @@ mod tests {
         .unwrap();
     }
 
+    #[tokio::test]
+    async fn test_not_allowed_through_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let config = Config::load(Some(UserConfig {
+            push_proxy: Some(proxy.parse().unwrap()),
+            ..Default::default()
+        }))
+        .unwrap();
+        let res = post_allowed(
+            &config,
+            Url::from_str("http://127.0.0.1:8080/push").unwrap(),
+            &[("type", "message")],
+        )
+        .await;
+        assert!(res.is_err());
+        // The request did not reach the proxy
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), listener.accept())
+                .await
+                .is_err()
+        );
+    }
+
+    /// Reads a request head, then answers `response`. Returns the head.
+    async fn answer(stream: &mut tokio::net::TcpStream, response: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_to_resolved_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let connect = answer(&mut stream, "HTTP/1.1 200 OK\r\n\r\n").await;
+            let request = answer(
+                &mut stream,
+                "HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await;
+            (connect, request)
+        });
+        let config = Config::load(Some(UserConfig {
+            push_proxy: Some(proxy.parse().unwrap()),
+            ..Default::default()
+        }))
+        .unwrap();
+        let resp = post_allowed(
+            &config,
+            Url::from_str("http://1.1.1.1:8080/push?id=1").unwrap(),
+            &[("type", "message")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), 201);
+
+        let (connect, request) = handle.await.unwrap();
+        assert!(connect.starts_with("CONNECT 1.1.1.1:8080 HTTP/1.1\r\n"));
+        assert!(request.starts_with("POST /push?id=1 HTTP/1.1\r\n"));
+        assert!(request.contains("host: 1.1.1.1:8080\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_allowed_not_proxied() {
+        let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", proxy_listener.local_addr().unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            answer(
+                &mut stream,
+                "HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n",
+            )
+            .await;
+        });
+        let config = Config::load(Some(UserConfig {
+            push_proxy: Some(proxy.parse().unwrap()),
+            allowed_endpoints: vec![endpoint.clone()],
+            ..Default::default()
+        }))
+        .unwrap();
+        let resp = post_allowed(
+            &config,
+            Url::from_str(&endpoint).unwrap(),
+            &[("type", "message")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), 201);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), proxy_listener.accept())
+                .await
+                .is_err()
+        );
+    }
+
     #[tokio::test]
     async fn test_not_allowed() {
         assert_eq!(len_from_str("unix://signal.org").await, 0);
@@ -0,0 +1,306 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::{eyre, Result};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::SocketAddr, str::FromStr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{self, TcpStream},
+};
+use tokio_socks::{tcp::Socks5Stream, TargetAddr};
+use url::{Host, Url};
+
+/// The response head of a proxy is short, a longer one is not a proxy.
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// How the connections are tunneled through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// HTTP CONNECT.
+    Http,
+    /// SOCKS5, the destination is resolved locally.
+    Socks5,
+    /// SOCKS5, the destination is resolved by the proxy.
+    Socks5h,
+}
+
+/// An outgoing proxy, written `http://host:port`, `socks5://host:port` or
+/// `socks5h://host:port`, with optional `user:password@` credentials.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Proxy {
+    kind: ProxyKind,
+    url: Url,
+}
+
+impl Proxy {
+    pub fn kind(&self) -> ProxyKind {
+        self.kind
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// The proxy, without its credentials, to be logged.
+    pub fn address(&self) -> String {
+        format!(
+            "{}://{}:{}",
+            self.url.scheme(),
+            self.url.host_str().unwrap_or_default(),
+            self.port()
+        )
+    }
+
+    /// The same proxy, with other credentials.
+    pub fn with_credentials(&self, user: &str, password: &str) -> Self {
+        let mut url = self.url.clone();
+        // Only fails for URLs without host, refused when parsed
+        let _ = url.set_username(user);
+        let _ = url.set_password(Some(password));
+        Self {
+            kind: self.kind,
+            url,
+        }
+    }
+
+    fn port(&self) -> u16 {
+        match (self.url.port(), self.kind) {
+            (Some(port), _) => port,
+            (None, ProxyKind::Http) => 80,
+            (None, _) => 1080,
+        }
+    }
+
+    fn credentials(&self) -> Option<(String, String)> {
+        if self.url.username().is_empty() {
+            return None;
+        }
+        let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+        Some((
+            decode(self.url.username()),
+            decode(self.url.password().unwrap_or_default()),
+        ))
+    }
+
+    /// Open a TCP stream to `host:port` through the proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let target = if self.kind == ProxyKind::Socks5 {
+            TargetAddr::Ip(resolve(host, port).await?)
+        } else {
+            TargetAddr::Domain(host.into(), port)
+        };
+        self.tunnel(target).await
+    }
+
+    /// Open a TCP stream to `addr` through the proxy, which doesn't resolve anything.
+    pub async fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream> {
+        self.tunnel(TargetAddr::Ip(addr)).await
+    }
+
+    async fn tunnel(&self, target: TargetAddr<'_>) -> Result<TcpStream> {
+        // Without the brackets of the IPv6 addresses
+        let proxy_host = match self.url.host() {
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            _ => self.url.host_str().unwrap_or_default().to_string(),
+        };
+        let mut stream = TcpStream::connect((proxy_host.as_str(), self.port())).await?;
+        let credentials = self.credentials();
+        match self.kind {
+            ProxyKind::Http => {
+                let authority = match &target {
+                    TargetAddr::Ip(addr) => addr.to_string(),
+                    TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
+                };
+                http_connect(&mut stream, &authority, credentials).await?;
+                Ok(stream)
+            }
+            ProxyKind::Socks5 | ProxyKind::Socks5h => {
+                let stream = match credentials {
+                    Some((user, password)) => {
+                        Socks5Stream::connect_with_password_and_socket(
+                            stream, target, &user, &password,
+                        )
+                        .await?
+                    }
+                    None => Socks5Stream::connect_with_socket(stream, target).await?,
+                };
+                Ok(stream.into_inner())
+            }
+        }
+    }
+}
+
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| eyre!("Could not resolve {}", host))
+}
+
+/// Ask an HTTP proxy to open a tunnel to `authority`, written `host:port`.
+async fn http_connect(
+    stream: &mut TcpStream,
+    authority: &str,
+    credentials: Option<(String, String)>,
+) -> Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some((user, password)) = credentials {
+        let token = STANDARD.encode(format!("{}:{}", user, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte: what follows the head belongs to the tunnel
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(eyre!("The proxy response is too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut status_line = head.split_whitespace();
+    match (status_line.next(), status_line.next()) {
+        (Some(version), Some("200")) if version.starts_with("HTTP/1.") => Ok(()),
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => Err(eyre!(
+            "The proxy refused to connect to {}, with the status {}",
+            authority,
+            status
+        )),
+        _ => Err(eyre!("Invalid response from the proxy")),
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let url = Url::parse(s)?;
+        let kind = match url.scheme() {
+            "http" => ProxyKind::Http,
+            "socks5" => ProxyKind::Socks5,
+            "socks5h" => ProxyKind::Socks5h,
+            scheme => return Err(eyre!("Unsupported proxy scheme: {}", scheme)),
+        };
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(eyre!("The proxy has no host"));
+        }
+        if !matches!(url.path(), "" | "/") || url.query().is_some() {
+            return Err(eyre!("The proxy must be written <scheme>://host:port"));
+        }
+        Ok(Self { kind, url })
+    }
+}
+
+impl TryFrom<String> for Proxy {
+    type Error = eyre::Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl From<Proxy> for String {
+    fn from(proxy: Proxy) -> Self {
+        proxy.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncBufReadExt, io::BufReader, net::TcpListener};
+
+    #[test]
+    fn parse_proxies() {
+        let proxy: Proxy = "http://proxy.lan:3128".parse().unwrap();
+        assert_eq!(proxy.kind(), ProxyKind::Http);
+        assert_eq!(proxy.port(), 3128);
+        assert_eq!(proxy.credentials(), None);
+
+        let proxy: Proxy = "socks5h://us%40er:p%3Ass@[::1]".parse().unwrap();
+        assert_eq!(proxy.kind(), ProxyKind::Socks5h);
+        assert_eq!(proxy.port(), 1080);
+        assert_eq!(
+            proxy.credentials(),
+            Some((String::from("us@er"), String::from("p:ss")))
+        );
+        assert_eq!(proxy.address(), "socks5h://[::1]:1080");
+        let proxy = proxy.with_credentials("a1b2", "p@ss");
+        assert_eq!(
+            proxy.credentials(),
+            Some((String::from("a1b2"), String::from("p@ss")))
+        );
+
+        for proxy in [
+            "",
+            "proxy.lan:3128",
+            "https://proxy.lan",
+            "socks4://proxy.lan",
+            "http://proxy.lan/path",
+        ] {
+            assert!(proxy.parse::<Proxy>().is_err(), "{}", proxy);
+        }
+    }
+
+    /// Answers the CONNECT request with `status`, then echoes the tunnel.
+    async fn http_proxy(status: &'static str) -> (Proxy, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://user:pass@{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request = String::new();
+            while !request.ends_with("\r\n\r\n") {
+                stream.read_line(&mut request).await.unwrap();
+            }
+            let response = format!("HTTP/1.1 {}\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let mut echo = [0; 4];
+            if stream.read_exact(&mut echo).await.is_ok() {
+                stream.write_all(&echo).await.unwrap();
+            }
+            request
+        });
+        (proxy.parse().unwrap(), handle)
+    }
+
+    #[tokio::test]
+    async fn http_tunnel() {
+        let (proxy, handle) = http_proxy("200 Connection established").await;
+        let mut stream = proxy.connect("chat.signal.org", 443).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("CONNECT chat.signal.org:443 HTTP/1.1\r\n"));
+        // base64 of user:pass
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn http_tunnel_addr() {
+        let (proxy, handle) = http_proxy("200 Connection established").await;
+        let addr = "[2001:db8::1]:443".parse().unwrap();
+        drop(proxy.connect_addr(addr).await.unwrap());
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn http_tunnel_refused() {
+        let (proxy, _handle) = http_proxy("403 Forbidden").await;
+        let e = proxy.connect("chat.signal.org", 443).await.unwrap_err();
+        assert!(e.to_string().contains("403"), "{}", e);
+    }
+}
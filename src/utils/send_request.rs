@@ -0,0 +1,14 @@
+use eyre::Result;
+use hyper::{client::conn, Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Send a single HTTP/1 request on an open stream, such as a unix socket or a
+/// tunnel through a proxy, which the reqwest client can't use.
+pub async fn send_request<S>(stream: S, req: Request<Body>) -> Result<Response<Body>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = conn::handshake(stream).await?;
+    tokio::spawn(conn);
+    Ok(sender.send_request(req).await?)
+}
@@ -0,0 +1,101 @@
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+use crate::{config::Config, utils::post_allowed::post_allowed};
+
+/// A push server not answering within this delay is unreachable.
+const TEST_PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TestPush {
+    /// The push server accepted the notification, with this id.
+    Accepted(String),
+    /// The request could not be sent, or timed out.
+    Unreachable,
+    /// The push server answered with this HTTP status.
+    Rejected(u16),
+}
+
+impl TestPush {
+    fn from_status(status: u16, id: String) -> Self {
+        if (200..300).contains(&status) {
+            TestPush::Accepted(id)
+        } else {
+            TestPush::Rejected(status)
+        }
+    }
+}
+
+/// UnifiedPush servers answer 404 or 410 once the endpoint is removed.
+pub fn is_endpoint_gone(status: u16) -> bool {
+    matches!(status, 404 | 410)
+}
+
+/// Send a notification of type `test` to the endpoint. Its `id` lets the app
+/// confirm it received it.
+pub async fn test_push(config: &Config, endpoint: &str) -> TestPush {
+    let url = match url::Url::parse(endpoint) {
+        Ok(url) => url,
+        Err(_) => return TestPush::Unreachable,
+    };
+    let id = test_id(endpoint);
+    let body = [("type", "test"), ("id", id.as_str())];
+    match time::timeout(TEST_PUSH_TIMEOUT, post_allowed(config, url, &body)).await {
+        Ok(Ok(resp)) => TestPush::from_status(resp.status().as_u16(), id),
+        Ok(Err(e)) => {
+            tracing::info!(event = "test_push_failed", error = %e, "Could not send the test notification");
+            TestPush::Unreachable
+        }
+        Err(_) => {
+            tracing::info!(
+                event = "test_push_timeout",
+                "The test notification timed out"
+            );
+            TestPush::Unreachable
+        }
+    }
+}
+
+fn test_id(endpoint: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    Sha256::digest(format!("{}{}", endpoint, nanos).as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_classes() {
+        assert_eq!(
+            TestPush::from_status(201, String::from("id")),
+            TestPush::Accepted(String::from("id"))
+        );
+        assert_eq!(
+            TestPush::from_status(404, String::from("id")),
+            TestPush::Rejected(404)
+        );
+        assert_eq!(
+            TestPush::from_status(302, String::from("id")),
+            TestPush::Rejected(302)
+        );
+        assert!(is_endpoint_gone(410));
+        assert!(!is_endpoint_gone(500));
+    }
+
+    #[tokio::test]
+    async fn invalid_endpoint() {
+        let config = Config::load(Some(Default::default())).unwrap();
+        assert_eq!(
+            test_push(&config, "not an url").await,
+            TestPush::Unreachable
+        );
+    }
+}
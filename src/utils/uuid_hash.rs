@@ -0,0 +1,22 @@
+use sha2::{Digest, Sha256};
+
+/// Short identifier of an account, usable in logs and metrics without revealing its UUID.
+pub fn uuid_hash(uuid: &str) -> String {
+    Sha256::digest(uuid.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hash() {
+        let hash = uuid_hash("0d2ff653-3d88-43de-bcdb-f6657d3484e4");
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, uuid_hash("0d2ff653-3d88-43de-bcdb-f6657d3484e4"));
+        assert_ne!(hash, uuid_hash("11111111-3d88-43de-bcdb-f6657d3484e4"));
+    }
+}
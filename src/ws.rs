@@ -1,6 +1,11 @@
+mod debounce;
 mod signalwebsocket;
 mod tls;
 mod websocket_connection;
 mod websocket_message;
 
-pub use signalwebsocket::SignalWebSocket;
+pub use debounce::{Debounce, PushPolicy};
+pub use signalwebsocket::{
+    check_credentials, CheckCredentials, PushResult, Reconnection, SignalWebSocket,
+};
+pub use websocket_connection::Disconnection;
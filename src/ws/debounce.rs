@@ -0,0 +1,297 @@
+use eyre::{eyre, Result};
+use futures_util::{select, Future, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, sync::Mutex, time::Duration};
+use tokio::{
+    sync::Notify,
+    time::{self, Instant},
+};
+
+/// When an envelope from Signal server triggers a push.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Debounce {
+    /// Push for every envelope.
+    Every,
+    /// Push if the previous push is older than the duration, the other envelopes are dropped.
+    Window(Duration),
+    /// Push for the first envelope after no envelope was received for the duration.
+    Quiet(Duration),
+}
+
+/// A debounce, and whether a push is sent at the end of the window, or of the
+/// quiet period, when envelopes have been dropped.
+///
+/// Written `every`, `window:<secs>` or `quiet:<secs>`, followed by `:trailing`
+/// for the trailing push.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PushPolicy {
+    pub debounce: Debounce,
+    pub trailing: bool,
+}
+
+impl Default for PushPolicy {
+    fn default() -> Self {
+        Self {
+            debounce: Debounce::Window(Duration::from_secs(5)),
+            trailing: false,
+        }
+    }
+}
+
+impl FromStr for PushPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (s, trailing) = match s.strip_suffix(":trailing") {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let secs = |secs: &str| {
+            secs.parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| eyre!("Invalid number of seconds: {}", secs))
+        };
+        let debounce = match s.split_once(':') {
+            None if s == "every" && !trailing => Debounce::Every,
+            Some(("window", duration)) => Debounce::Window(secs(duration)?),
+            Some(("quiet", duration)) => Debounce::Quiet(secs(duration)?),
+            _ => return Err(eyre!("Invalid push policy: {}", s)),
+        };
+        Ok(Self { debounce, trailing })
+    }
+}
+
+impl TryFrom<String> for PushPolicy {
+    type Error = eyre::Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for PushPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.debounce {
+            Debounce::Every => write!(f, "every")?,
+            Debounce::Window(duration) => write!(f, "window:{}", duration.as_secs())?,
+            Debounce::Quiet(duration) => write!(f, "quiet:{}", duration.as_secs())?,
+        }
+        if self.trailing {
+            write!(f, ":trailing")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<PushPolicy> for String {
+    fn from(policy: PushPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+/// Decides, for each envelope, if a push must be sent.
+#[derive(Debug)]
+pub struct Debouncer {
+    policy: PushPolicy,
+    last_push: Option<Instant>,
+    last_envelope: Option<Instant>,
+    /// Envelopes have been dropped since the last push.
+    pending: bool,
+}
+
+impl Debouncer {
+    pub fn new(policy: PushPolicy) -> Self {
+        Self {
+            policy,
+            last_push: None,
+            last_envelope: None,
+            pending: false,
+        }
+    }
+
+    /// An envelope is received at `now`, returns true if a push must be sent.
+    pub fn on_envelope(&mut self, now: Instant) -> bool {
+        let elapsed = |since: Option<Instant>, duration: Duration| {
+            since.is_none_or(|since| now.duration_since(since) >= duration)
+        };
+        let push = match self.policy.debounce {
+            Debounce::Every => true,
+            Debounce::Window(duration) => elapsed(self.last_push, duration),
+            Debounce::Quiet(duration) => elapsed(self.last_envelope, duration),
+        };
+        self.last_envelope = Some(now);
+        if push {
+            self.last_push = Some(now);
+        }
+        self.pending = !push && self.policy.trailing;
+        push
+    }
+
+    /// When the trailing push is due, if one is needed.
+    pub fn trailing_deadline(&self) -> Option<Instant> {
+        if !self.pending {
+            return None;
+        }
+        match self.policy.debounce {
+            Debounce::Every => None,
+            Debounce::Window(duration) => self.last_push.map(|since| since + duration),
+            Debounce::Quiet(duration) => self.last_envelope.map(|since| since + duration),
+        }
+    }
+
+    /// Returns true if the trailing push is due at `now`, it is then considered sent.
+    pub fn on_deadline(&mut self, now: Instant) -> bool {
+        match self.trailing_deadline() {
+            Some(deadline) if deadline <= now => {
+                self.pending = false;
+                self.last_push = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if a trailing push is pending, even before its deadline, it is
+    /// then considered sent.
+    pub fn take_pending(&mut self, now: Instant) -> bool {
+        if self.trailing_deadline().is_none() {
+            return false;
+        }
+        self.pending = false;
+        self.last_push = Some(now);
+        true
+    }
+}
+
+/// Call `push` when a trailing push is due. `debounced` is notified when an envelope
+/// is dropped, the deadline may have moved. Never returns.
+pub async fn trailing_pushes<F, Fut>(debouncer: &Mutex<Debouncer>, debounced: &Notify, push: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        let deadline = debouncer.lock().unwrap().trailing_deadline();
+        match deadline {
+            Some(deadline) => select!(
+                _ = time::sleep_until(deadline).fuse() => {
+                    let due = debouncer.lock().unwrap().on_deadline(Instant::now());
+                    if due {
+                        tracing::debug!(event = "trailing_push", "Sending the trailing notification.");
+                        push().await;
+                    }
+                },
+                _ = debounced.notified().fuse() => (),
+            ),
+            None => debounced.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn pushes(policy: &str, envelopes: &[u64]) -> Vec<bool> {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(policy.parse().unwrap());
+        envelopes
+            .iter()
+            .map(|secs| debouncer.on_envelope(start + Duration::from_secs(*secs)))
+            .collect()
+    }
+
+    #[test]
+    fn every_envelope() {
+        assert_eq!(pushes("every", &[0, 1, 2]), vec![true; 3]);
+    }
+
+    #[test]
+    fn window() {
+        assert_eq!(
+            pushes("window:5", &[0, 2, 4, 5, 9, 11]),
+            vec![true, false, false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn quiet_period() {
+        // The envelopes keep the connection busy
+        assert_eq!(
+            pushes("quiet:5", &[0, 4, 8, 12, 20]),
+            vec![true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn trailing_push() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut debouncer = Debouncer::new("window:5:trailing".parse().unwrap());
+
+        assert!(debouncer.on_envelope(at(0)));
+        assert_eq!(debouncer.trailing_deadline(), None);
+        assert!(!debouncer.on_envelope(at(4)));
+        assert_eq!(debouncer.trailing_deadline(), Some(at(5)));
+        assert!(!debouncer.on_deadline(at(4)));
+        assert!(debouncer.on_deadline(at(5)));
+        assert_eq!(debouncer.trailing_deadline(), None);
+        // The trailing push opens a new window
+        assert!(!debouncer.on_envelope(at(6)));
+        assert_eq!(debouncer.trailing_deadline(), Some(at(10)));
+        assert!(debouncer.take_pending(at(7)));
+        assert!(!debouncer.take_pending(at(7)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trailing_push_sent() {
+        let debouncer = Mutex::new(Debouncer::new("window:5:trailing".parse().unwrap()));
+        let debounced = Notify::new();
+        let pushes = AtomicU32::new(0);
+        let trailing = trailing_pushes(&debouncer, &debounced, || async {
+            pushes.fetch_add(1, Ordering::SeqCst);
+        });
+        let envelopes = async {
+            assert!(debouncer.lock().unwrap().on_envelope(Instant::now()));
+            time::sleep(Duration::from_millis(4900)).await;
+            // Dropped, and nothing follows
+            assert!(!debouncer.lock().unwrap().on_envelope(Instant::now()));
+            debounced.notify_one();
+            time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(pushes.load(Ordering::SeqCst), 0);
+            time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(pushes.load(Ordering::SeqCst), 1);
+            time::sleep(Duration::from_secs(60)).await;
+            assert_eq!(pushes.load(Ordering::SeqCst), 1);
+        };
+        select!(
+            _ = trailing.fuse() => unreachable!(),
+            _ = envelopes.fuse() => (),
+        );
+    }
+
+    #[test]
+    fn parse_policies() {
+        for policy in [
+            "every",
+            "window:5",
+            "window:5:trailing",
+            "quiet:30:trailing",
+        ] {
+            assert_eq!(policy.parse::<PushPolicy>().unwrap().to_string(), policy);
+        }
+        assert_eq!(PushPolicy::default().to_string(), "window:5");
+        for policy in [
+            "",
+            "every:trailing",
+            "window",
+            "window:",
+            "quiet:a",
+            "burst:5",
+        ] {
+            assert!(policy.parse::<PushPolicy>().is_err(), "{}", policy);
+        }
+    }
+}
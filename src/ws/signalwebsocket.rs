@@ -1,28 +1,100 @@
 use async_trait::async_trait;
-use eyre::Result;
+use eyre::{eyre, Result};
 use futures_channel::mpsc;
+use futures_util::{select, FutureExt};
 use std::{
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tokio::time;
+use tokio::{
+    sync::{watch, Notify},
+    time,
+};
 use tokio_tungstenite::tungstenite;
+use tracing::Instrument;
 
+use super::debounce::{trailing_pushes, Debouncer, PushPolicy};
 use super::tls;
-use super::websocket_connection::WebSocketConnection;
+use super::websocket_connection::{
+    handshake, shutdown_requested, Disconnection, Keepalive, WebSocketConnection,
+};
 use super::websocket_message::{
     webSocketMessage::Type, WebSocketMessage, WebSocketRequestMessage, WebSocketResponseMessage,
 };
-use crate::utils::post_allowed::post_allowed;
+use crate::{
+    config::Config,
+    utils::{post_allowed::post_allowed, proxy::Proxy},
+};
+
+/// Outcome of a request to the push endpoint.
+#[derive(Debug)]
+pub struct PushResult {
+    pub host: String,
+    /// HTTP status, None if the request failed
+    pub status: Option<u16>,
+    pub duration: Duration,
+}
+
+/// A websocket ended, or could not be opened: a new one is opened after `delay`.
+#[derive(Debug)]
+pub struct Reconnection {
+    pub cause: Disconnection,
+    pub delay: Duration,
+}
+
+/// Whether Signal server accepts the credentials of a linked device.
+#[derive(Debug)]
+pub enum CheckCredentials {
+    Ok,
+    /// Signal server refused the credentials, with this HTTP status.
+    Forbidden(u16),
+    /// The websocket could not be opened, or Signal server did not answer in time.
+    Unreachable(eyre::Report),
+}
 
-const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Open a websocket with the credentials, with the same handshake as the connection
+/// loop. Signal server must answer within `check_credentials_timeout`.
+pub async fn check_credentials(
+    config: &Config,
+    uuid: &str,
+    device_id: u32,
+    password: &str,
+) -> CheckCredentials {
+    let timeout = Duration::from_secs(config.user_cfg.check_credentials_timeout);
+    let check = async {
+        let url = url::Url::parse(&config.get_ws_endpoint(uuid, device_id, password))?;
+        let proxy = config.signal_proxy(uuid);
+        let mut ws_stream = handshake(&url, tls::build_tls_connector()?, proxy.as_ref()).await?;
+        let _ = ws_stream.close(None).await;
+        Ok::<(), eyre::Report>(())
+    };
+    match time::timeout(timeout, check).await {
+        Ok(Ok(())) => CheckCredentials::Ok,
+        Ok(Err(e)) => match e.downcast_ref::<tungstenite::Error>() {
+            Some(tungstenite::Error::Http(resp))
+                if resp.status() == 401 || resp.status() == 403 =>
+            {
+                CheckCredentials::Forbidden(resp.status().as_u16())
+            }
+            _ => CheckCredentials::Unreachable(e),
+        },
+        Err(_) => CheckCredentials::Unreachable(eyre!(
+            "Signal server did not answer within {}s",
+            timeout.as_secs()
+        )),
+    }
+}
 
 #[derive(Debug)]
 pub struct Channels {
     ws_tx: Option<mpsc::UnboundedSender<tungstenite::Message>>,
     pub on_message_tx: Option<mpsc::UnboundedSender<u32>>,
-    pub on_push_tx: Option<mpsc::UnboundedSender<u32>>,
-    pub on_reconnection_tx: Option<mpsc::UnboundedSender<u32>>,
+    pub on_push_tx: Option<mpsc::UnboundedSender<PushResult>>,
+    /// Round-trip time of the answered keepalives.
+    pub on_keepalive_tx: Option<mpsc::UnboundedSender<Duration>>,
+    pub on_reconnection_tx: Option<mpsc::UnboundedSender<Reconnection>>,
+    pub on_connection_tx: Option<mpsc::UnboundedSender<bool>>,
+    pub shutdown_rx: Option<watch::Receiver<bool>>,
 }
 
 impl Channels {
@@ -31,18 +103,26 @@ impl Channels {
             ws_tx: None,
             on_message_tx: None,
             on_push_tx: None,
+            on_keepalive_tx: None,
             on_reconnection_tx: None,
+            on_connection_tx: None,
+            shutdown_rx: None,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct SignalWebSocket {
+    config: Arc<Config>,
     connect_addr: url::Url,
+    /// Resolved for the UUID, see [Config::signal_proxy].
+    proxy: Option<Proxy>,
     push_endpoint: url::Url,
     pub channels: Channels,
-    push_instant: Arc<Mutex<Instant>>,
-    last_keepalive: Arc<Mutex<Instant>>,
+    debouncer: Arc<Mutex<Debouncer>>,
+    /// Notified when an envelope is debounced, a trailing push may be due.
+    debounced: Arc<Notify>,
+    keepalive: Arc<Mutex<Keepalive>>,
 }
 
 #[async_trait(?Send)]
@@ -59,8 +139,22 @@ impl WebSocketConnection for SignalWebSocket {
         self.channels.ws_tx = tx;
     }
 
-    fn get_last_keepalive(&self) -> Arc<Mutex<Instant>> {
-        Arc::clone(&self.last_keepalive)
+    fn get_keepalive(&self) -> Arc<Mutex<Keepalive>> {
+        Arc::clone(&self.keepalive)
+    }
+
+    fn get_proxy(&self) -> Option<Proxy> {
+        self.proxy.clone()
+    }
+
+    fn get_shutdown_rx(&self) -> Option<watch::Receiver<bool>> {
+        self.channels.shutdown_rx.clone()
+    }
+
+    fn on_connection_change(&self, connected: bool) {
+        if let Some(tx) = &self.channels.on_connection_tx {
+            let _ = tx.unbounded_send(connected);
+        }
     }
 
     async fn on_message(&self, message: WebSocketMessage) {
@@ -77,56 +171,138 @@ impl WebSocketConnection for SignalWebSocket {
 }
 
 impl SignalWebSocket {
-    pub fn new(connect_addr: String, push_endpoint: String) -> Result<Self> {
-        let connect_addr = url::Url::parse(&connect_addr)?;
+    pub fn new(
+        config: Arc<Config>,
+        uuid: &str,
+        device_id: u32,
+        password: &str,
+        push_endpoint: String,
+    ) -> Result<Self> {
+        let connect_addr = url::Url::parse(&config.get_ws_endpoint(uuid, device_id, password))?;
+        let proxy = config.signal_proxy(uuid);
         let push_endpoint = url::Url::parse(&push_endpoint)?;
+        let keepalive = Keepalive::new(
+            Duration::from_secs(config.user_cfg.keepalive_interval),
+            Duration::from_secs(config.user_cfg.keepalive_timeout),
+        );
         Ok(Self {
+            config,
             connect_addr,
+            proxy,
             push_endpoint,
             channels: Channels::none(),
-            push_instant: Arc::new(Mutex::new(
-                Instant::now().checked_sub(PUSH_TIMEOUT).unwrap(),
-            )),
-            last_keepalive: Arc::new(Mutex::new(Instant::now())),
+            debouncer: Arc::new(Mutex::new(Debouncer::new(PushPolicy::default()))),
+            debounced: Arc::new(Notify::new()),
+            keepalive: Arc::new(Mutex::new(keepalive)),
         })
     }
 
+    pub fn set_push_policy(&mut self, policy: PushPolicy) {
+        self.debouncer = Arc::new(Mutex::new(Debouncer::new(policy)));
+    }
+
     pub async fn connection_loop(&mut self) -> Result<()> {
+        // The trailing pushes are sent even if the websocket is closed meanwhile
+        let config = Arc::clone(&self.config);
+        let push_endpoint = self.push_endpoint.clone();
+        let on_push_tx = self.channels.on_push_tx.clone();
+        let debouncer = Arc::clone(&self.debouncer);
+        let debounced = Arc::clone(&self.debounced);
+        let push = || send_push(&config, &push_endpoint, on_push_tx.as_ref());
+        let trailing = trailing_pushes(&debouncer, &debounced, &push);
+        let res = select!(
+            res = self.reconnection_loop().fuse() => res,
+            _ = trailing.fuse() => Ok(()),
+        );
+        // The loop stops on shutdown or when forbidden: the pending trailing push is
+        // sent without waiting for its deadline
+        let pending = debouncer.lock().unwrap().take_pending(time::Instant::now());
+        if pending {
+            tracing::debug!(
+                event = "trailing_push",
+                "Sending the trailing notification."
+            );
+            push().await;
+        }
+        res
+    }
+
+    async fn reconnection_loop(&mut self) -> Result<()> {
         let mut count = 0;
         loop {
             let instant = Instant::now();
+            self.keepalive.lock().unwrap().reset();
+            self.on_connection_change(false);
+            let session = tracing::info_span!("session", retries = count);
+            let disconnection = match self
+                .connect(tls::build_tls_connector()?)
+                .instrument(session)
+                .await
             {
-                let mut keepalive = self.last_keepalive.lock().unwrap();
-                *keepalive = Instant::now();
-            }
-            if let Err(e) = self.connect(tls::build_tls_connector()?).await {
-                if let Some(http_error) = e.downcast_ref::<tungstenite::Error>() {
-                    if let tungstenite::Error::Http(resp) = http_error {
+                Ok(disconnection) => disconnection,
+                Err(e) => {
+                    if let Some(tungstenite::Error::Http(resp)) =
+                        e.downcast_ref::<tungstenite::Error>()
+                    {
                         if resp.status() == 403 {
                             return Err(e);
                         }
                     }
+                    tracing::info!(event = "connection_failed", error = %e, "Could not connect");
+                    Disconnection::from_error(&e)
                 }
+            };
+            if self.is_shutting_down() {
+                return Ok(());
             }
             if let Some(duration) = Instant::now().checked_duration_since(instant) {
                 if duration > Duration::from_secs(60) {
                     count = 0;
                 }
             }
+            count += 1;
+            let delay = Duration::from_secs(count * 10);
+            tracing::info!(
+                event = "reconnection",
+                cause = disconnection.as_str(),
+                "Retrying to connect in {}0 secondes.",
+                count
+            );
             if let Some(tx) = &self.channels.on_reconnection_tx {
-                let _ = tx.unbounded_send(1);
+                let _ = tx.unbounded_send(Reconnection {
+                    cause: disconnection,
+                    delay,
+                });
             }
-            count += 1;
-            log::info!("Retrying to connect in {}0 secondes.", count);
-            time::sleep(Duration::from_secs(count * 10)).await;
+            select!(
+                _ = time::sleep(delay).fuse() => (),
+                _ = shutdown_requested(self.get_shutdown_rx()).fuse() => return Ok(()),
+            );
         }
     }
 
+    fn is_shutting_down(&self) -> bool {
+        self.channels
+            .shutdown_rx
+            .as_ref()
+            .is_some_and(|rx| *rx.borrow())
+    }
+
     fn on_response(&self, response: Option<WebSocketResponseMessage>) {
-        log::debug!("New response");
-        if let Some(_) = response {
-            let mut keepalive = self.last_keepalive.lock().unwrap();
-            *keepalive = Instant::now();
+        tracing::debug!(event = "response", "New response");
+        let Some(response) = response else {
+            return;
+        };
+        let rtt = self.keepalive.lock().unwrap().on_response(response.id);
+        if let Some(rtt) = rtt {
+            tracing::debug!(
+                event = "keepalive_response",
+                rtt_ms = rtt.as_millis() as u64,
+                "Keepalive answered"
+            );
+            if let Some(tx) = &self.channels.on_keepalive_tx {
+                let _ = tx.unbounded_send(rtt);
+            }
         }
     }
 
@@ -134,16 +310,30 @@ impl SignalWebSocket {
      * That's when we must send a notification
      */
     async fn on_request(&self, request: Option<WebSocketRequestMessage>) {
-        log::debug!("New request");
+        tracing::debug!(event = "request", "New request");
         if let Some(request) = request {
             if self.read_or_empty(request).await {
                 if let Some(tx) = &self.channels.on_message_tx {
                     let _ = tx.unbounded_send(1);
                 }
-                if self.waiting_timeout_reached() {
-                    self.send_push().await;
+                let push = self
+                    .debouncer
+                    .lock()
+                    .unwrap()
+                    .on_envelope(time::Instant::now());
+                if push {
+                    send_push(
+                        &self.config,
+                        &self.push_endpoint,
+                        self.channels.on_push_tx.as_ref(),
+                    )
+                    .await;
                 } else {
-                    log::debug!("The waiting timeout is not reached: the request is ignored.");
+                    self.debounced.notify_one();
+                    tracing::debug!(
+                        event = "push_skipped",
+                        "The push is debounced: the request is ignored."
+                    );
                 }
             }
         }
@@ -199,23 +389,78 @@ impl SignalWebSocket {
             body: None,
         }
     }
+}
 
-    async fn send_push(&self) {
-        log::debug!("Sending the notification.");
-        {
-            let mut instant = self.push_instant.lock().unwrap();
-            *instant = Instant::now();
+#[tracing::instrument(name = "push", skip_all, fields(host = push_endpoint.host_str(), status))]
+async fn send_push(
+    config: &Config,
+    push_endpoint: &url::Url,
+    on_push_tx: Option<&mpsc::UnboundedSender<PushResult>>,
+) {
+    tracing::debug!(event = "push", "Sending the notification.");
+    let start = Instant::now();
+    let res = post_allowed(config, push_endpoint.clone(), &[("type", "message")]).await;
+    let status = match res {
+        Ok(resp) => Some(resp.status().as_u16()),
+        Err(e) => {
+            tracing::info!(event = "push_failed", error = %e, "Could not send the notification");
+            None
         }
+    };
+    if let Some(status) = status {
+        tracing::Span::current().record("status", status);
+    }
+    if let Some(tx) = on_push_tx {
+        let _ = tx.unbounded_send(PushResult {
+            host: push_endpoint.host_str().unwrap_or("").to_string(),
+            status,
+            duration: start.elapsed(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
 
-        let url = self.push_endpoint.clone();
-        let _ = post_allowed(url, &[("type", "message")]).await;
-        if let Some(tx) = &self.channels.on_push_tx {
-            let _ = tx.unbounded_send(1);
+    #[tokio::test(start_paused = true)]
+    async fn trailing_push_on_shutdown() {
+        // Nothing listens on the proxy: the websocket can't be opened
+        let config = Config::load(Some(UserConfig {
+            signal_proxy: Some("socks5h://127.0.0.1:1".parse().unwrap()),
+            ..Default::default()
+        }))
+        .unwrap();
+        let mut socket = SignalWebSocket::new(
+            Arc::new(config),
+            "0d2ff653-3d88-43de-bcdb-f6657d3484e4",
+            1,
+            "password",
+            String::from("http://127.0.0.1/push"),
+        )
+        .unwrap();
+        socket.set_push_policy("window:5:trailing".parse().unwrap());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (on_push_tx, mut on_push_rx) = mpsc::unbounded();
+        socket.channels.shutdown_rx = Some(shutdown_rx);
+        socket.channels.on_push_tx = Some(on_push_tx);
+        {
+            let mut debouncer = socket.debouncer.lock().unwrap();
+            assert!(debouncer.on_envelope(time::Instant::now()));
+            assert!(!debouncer.on_envelope(time::Instant::now()));
         }
-    }
 
-    fn waiting_timeout_reached(&self) -> bool {
-        let instant = self.push_instant.lock().unwrap();
-        instant.elapsed() > PUSH_TIMEOUT
+        let shutdown = async {
+            time::sleep(Duration::from_secs(1)).await;
+            shutdown_tx.send(true).unwrap();
+        };
+        let start = time::Instant::now();
+        let (res, ()) = futures_util::join!(socket.connection_loop(), shutdown);
+        res.unwrap();
+        // Sent before the deadline of the trailing push
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let push = on_push_rx.try_next().unwrap().unwrap();
+        assert_eq!(push.host, "127.0.0.1");
     }
 }
@@ -1,52 +1,136 @@
+use crate::utils::proxy::Proxy;
 use async_trait::async_trait;
-use eyre::Result;
+use eyre::{eyre, Result};
 use futures_channel::mpsc;
-use futures_util::{pin_mut, select, FutureExt, SinkExt, StreamExt};
+use futures_util::{future, pin_mut, select, FutureExt, SinkExt, StreamExt};
 use native_tls::TlsConnector;
 use prost::Message;
 use std::{
     sync::{Arc, Mutex},
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
 };
-use tokio::time;
+use tokio::{net::TcpStream, sync::watch, time};
 use tokio_tungstenite::{
-    tungstenite::{self, client::IntoClientRequest},
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+    },
     Connector::NativeTls,
+    MaybeTlsStream, WebSocketStream,
 };
 
 use super::websocket_message::{
     webSocketMessage::Type, WebSocketMessage, WebSocketRequestMessage, WebSocketResponseMessage,
 };
 
-const KEEPALIVE: Duration = Duration::from_secs(30);
-const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(40);
+/// The keepalives sent on a websocket. Signal server answers them with the same id.
+#[derive(Debug)]
+pub struct Keepalive {
+    pub interval: Duration,
+    /// A keepalive without response within this duration closes the websocket.
+    pub timeout: Duration,
+    last_id: u64,
+    /// The keepalive waiting for its response, and when it was sent.
+    pending: Option<(u64, Instant)>,
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            last_id: 0,
+            pending: None,
+        }
+    }
+
+    /// A keepalive is about to be sent, returns its id.
+    fn sent(&mut self) -> u64 {
+        self.last_id += 1;
+        self.pending = Some((self.last_id, Instant::now()));
+        self.last_id
+    }
+
+    fn is_pending(&self, id: u64) -> bool {
+        self.pending.is_some_and(|(pending, _)| pending == id)
+    }
+
+    /// A response is received, returns the round-trip time if it answers the pending keepalive.
+    pub fn on_response(&mut self, id: Option<u64>) -> Option<Duration> {
+        match self.pending {
+            Some((pending, sent)) if Some(pending) == id => {
+                self.pending = None;
+                Some(sent.elapsed())
+            }
+            _ => None,
+        }
+    }
+
+    /// A new websocket is opened.
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+/// Why a websocket ended, or could not be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disconnection {
+    KeepaliveTimeout,
+    CloseFrame,
+    ConnectionLost,
+    WriteError,
+    Shutdown,
+    HttpError,
+    TlsError,
+    OtherError,
+}
+
+impl Disconnection {
+    pub fn from_error(error: &eyre::Report) -> Self {
+        match error.downcast_ref::<tungstenite::Error>() {
+            Some(tungstenite::Error::Http(_)) => Disconnection::HttpError,
+            Some(tungstenite::Error::Tls(_)) => Disconnection::TlsError,
+            _ => Disconnection::OtherError,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Disconnection::KeepaliveTimeout => "keepalive_timeout",
+            Disconnection::CloseFrame => "close_frame",
+            Disconnection::ConnectionLost => "connection_lost",
+            Disconnection::WriteError => "write_error",
+            Disconnection::Shutdown => "shutdown",
+            Disconnection::HttpError => "http_error",
+            Disconnection::TlsError => "tls_error",
+            Disconnection::OtherError => "other_error",
+        }
+    }
+}
 
 #[async_trait(?Send)]
 pub trait WebSocketConnection {
     fn get_url(&self) -> &url::Url;
     fn get_websocket_tx(&self) -> &Option<mpsc::UnboundedSender<tungstenite::Message>>;
     fn set_websocket_tx(&mut self, tx: Option<mpsc::UnboundedSender<tungstenite::Message>>);
-    fn get_last_keepalive(&self) -> Arc<Mutex<Instant>>;
+    fn get_keepalive(&self) -> Arc<Mutex<Keepalive>>;
+    fn get_proxy(&self) -> Option<Proxy>;
+    fn get_shutdown_rx(&self) -> Option<watch::Receiver<bool>>;
+    fn on_connection_change(&self, connected: bool);
     async fn on_message(&self, message: WebSocketMessage);
 
-    async fn connect(&mut self, tls_connector: TlsConnector) -> Result<()> {
-        let mut request = self.get_url().into_client_request()?;
-
-        request
-            .headers_mut()
-            .insert("X-Signal-Agent", http::HeaderValue::from_static("\"OWA\""));
-
-        let (ws_stream, _) = tokio_tungstenite::connect_async_tls_with_config(
-            request,
-            None,
-            Some(NativeTls(tls_connector)),
-        )
-        .await?;
+    async fn connect(&mut self, tls_connector: TlsConnector) -> Result<Disconnection> {
+        let ws_stream = handshake(self.get_url(), tls_connector, self.get_proxy().as_ref()).await?;
 
-        log::info!("WebSocket handshake has been successfully completed");
+        tracing::info!(
+            event = "websocket_open",
+            "WebSocket handshake has been successfully completed"
+        );
+        self.on_connection_change(true);
 
         // Websocket I/O
-        let (ws_write, ws_read) = ws_stream.split();
+        let (ws_write, mut ws_read) = ws_stream.split();
         // channel to websocket ws_write
         let (tx, rx) = mpsc::unbounded();
         // other channels: msg, keepalive, abort
@@ -58,36 +142,74 @@ pub trait WebSocketConnection {
         // handlers
         let to_ws_handle = rx.map(Ok).forward(ws_write).fuse();
 
-        let from_ws_handle = ws_read
-            .for_each(|message| async {
-                log::debug!("New message");
-                if let Ok(message) = message {
-                    self.handle_message(message).await;
+        let from_ws_handle = async {
+            let mut close_frame = false;
+            while let Some(message) = ws_read.next().await {
+                tracing::debug!(event = "message", "New message");
+                match message {
+                    // Keep reading: the server closes the connection after our reply
+                    Ok(tungstenite::Message::Close(_)) => close_frame = true,
+                    Ok(message) => self.handle_message(message).await,
+                    Err(_) => (),
                 }
-            })
-            .fuse();
+            }
+            if close_frame {
+                Disconnection::CloseFrame
+            } else {
+                Disconnection::ConnectionLost
+            }
+        }
+        .fuse();
 
+        let this = &*self;
         let from_keepalive_handle = timer_rx
-            .for_each(|_| async { self.send_keepalive().await })
+            .for_each(|id| async move { this.send_keepalive(id).await })
             .fuse();
 
         let to_keepalive_handle = self.loop_keepalive(timer_tx).fuse();
 
+        let shutdown_handle = shutdown_requested(self.get_shutdown_rx()).fuse();
+
         pin_mut!(
             to_ws_handle,
             from_ws_handle,
             from_keepalive_handle,
-            to_keepalive_handle
+            to_keepalive_handle,
+            shutdown_handle
         );
 
         // handle websocket
-        select!(
-            _ = to_ws_handle => log::warn!("Messages finished"),
-            _ = from_ws_handle => log::warn!("Websocket finished"),
-            _ = from_keepalive_handle => log::warn!("Keepalive finished"),
-            _ = to_keepalive_handle => log::warn!("Keepalive finished"),
+        let disconnection = select!(
+            _ = to_ws_handle => {
+                tracing::warn!(event = "websocket_closed", "Messages finished");
+                Disconnection::WriteError
+            },
+            disconnection = from_ws_handle => {
+                tracing::warn!(event = "websocket_closed", "Websocket finished");
+                disconnection
+            },
+            _ = from_keepalive_handle => {
+                tracing::warn!(event = "websocket_closed", "Keepalive finished");
+                Disconnection::KeepaliveTimeout
+            },
+            _ = to_keepalive_handle => {
+                tracing::warn!(event = "websocket_closed", "Keepalive finished");
+                Disconnection::KeepaliveTimeout
+            },
+            _ = shutdown_handle => {
+                tracing::info!(event = "shutdown", "Shutting down: closing the websocket");
+                self.send_close().await;
+                // Wait for the server to acknowledge the close frame,
+                // the messages received meanwhile are still handled
+                select!(
+                    _ = to_ws_handle => (),
+                    _ = from_ws_handle => (),
+                );
+                Disconnection::Shutdown
+            },
         );
-        Ok(())
+        self.on_connection_change(false);
+        Ok(disconnection)
     }
 
     async fn handle_message(&self, message: tungstenite::Message) {
@@ -95,8 +217,8 @@ pub trait WebSocketConnection {
         let ws_message = match WebSocketMessage::decode(&data[..]) {
             Ok(msg) => msg,
             Err(e) => {
-                log::error!("Failed to decode protobuf: {}", e);
-                return ();
+                tracing::error!(event = "decode_failed", error = %e, "Failed to decode protobuf");
+                return;
             }
         };
         self.on_message(ws_message).await;
@@ -109,6 +231,16 @@ pub trait WebSocketConnection {
         }
     }
 
+    async fn send_close(&self) {
+        if let Some(mut tx) = self.get_websocket_tx().as_ref() {
+            let frame = CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            };
+            let _ = tx.send(tungstenite::Message::Close(Some(frame))).await;
+        }
+    }
+
     async fn send_response(&self, response: WebSocketResponseMessage) {
         let message = WebSocketMessage {
             r#type: Some(Type::RESPONSE as i32),
@@ -118,8 +250,8 @@ pub trait WebSocketConnection {
         self.send_message(message).await;
     }
 
-    async fn send_keepalive(&self) {
-        log::debug!("send_keepalive");
+    async fn send_keepalive(&self, id: u64) {
+        tracing::debug!(event = "keepalive", "send_keepalive");
         let message = WebSocketMessage {
             r#type: Some(Type::REQUEST as i32),
             response: None,
@@ -128,27 +260,101 @@ pub trait WebSocketConnection {
                 path: Some(String::from("/v1/keepalive")),
                 body: None,
                 headers: Vec::new(),
-                id: Some(
-                    SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64,
-                ),
+                id: Some(id),
             }),
         };
         self.send_message(message).await;
     }
 
-    async fn loop_keepalive(&self, timer_tx: mpsc::UnboundedSender<bool>) {
-        let last_keepalive = self.get_last_keepalive();
+    /// Sends a keepalive every interval, returns if one isn't answered in time.
+    async fn loop_keepalive(&self, timer_tx: mpsc::UnboundedSender<u64>) {
+        let keepalive = self.get_keepalive();
+        let (interval, timeout) = {
+            let keepalive = keepalive.lock().unwrap();
+            (keepalive.interval, keepalive.timeout)
+        };
+        let mut next = time::Instant::now() + interval;
         loop {
-            if last_keepalive.lock().unwrap().elapsed() > KEEPALIVE_TIMEOUT {
-                log::warn!("Did not receive the last keepalive: aborting.");
+            time::sleep_until(next).await;
+            next = time::Instant::now() + interval;
+            let id = keepalive.lock().unwrap().sent();
+            tracing::debug!(event = "keepalive", "Sending Keepalive");
+            timer_tx.unbounded_send(id).unwrap();
+            time::sleep(timeout).await;
+            if keepalive.lock().unwrap().is_pending(id) {
+                tracing::warn!(
+                    event = "keepalive_timeout",
+                    "Did not receive the last keepalive: aborting."
+                );
                 break;
             }
-            time::sleep(KEEPALIVE).await;
-            log::debug!("Sending Keepalive");
-            timer_tx.unbounded_send(true).unwrap();
         }
     }
 }
+
+/// Open a websocket, it is `Send` unlike the methods of [WebSocketConnection].
+pub async fn handshake(
+    url: &url::Url,
+    tls_connector: TlsConnector,
+    proxy: Option<&Proxy>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let mut request = url.into_client_request()?;
+
+    request
+        .headers_mut()
+        .insert("X-Signal-Agent", http::HeaderValue::from_static("\"OWA\""));
+
+    let connector = Some(NativeTls(tls_connector));
+    let (ws_stream, _) = match proxy {
+        Some(proxy) => {
+            let host = url.host_str().ok_or_else(|| eyre!("The URL has no host"))?;
+            let port = url.port_or_known_default().unwrap_or(443);
+            let stream = proxy.connect(host, port).await?;
+            tokio_tungstenite::client_async_tls_with_config(request, stream, None, connector)
+                .await?
+        }
+        None => tokio_tungstenite::connect_async_tls_with_config(request, None, connector).await?,
+    };
+    Ok(ws_stream)
+}
+
+/// Resolves when a shutdown is requested, never if there is no shutdown channel.
+pub async fn shutdown_requested(shutdown_rx: Option<watch::Receiver<bool>>) {
+    if let Some(mut rx) = shutdown_rx {
+        if rx.wait_for(|shutdown| *shutdown).await.is_ok() {
+            return;
+        }
+    }
+    future::pending::<()>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_responses() {
+        let mut keepalive = Keepalive::new(Duration::from_secs(30), Duration::from_secs(10));
+        let first = keepalive.sent();
+        // The response to another request
+        assert_eq!(keepalive.on_response(Some(first + 10)), None);
+        assert_eq!(keepalive.on_response(None), None);
+        assert!(keepalive.is_pending(first));
+        let second = keepalive.sent();
+        assert_ne!(first, second);
+        // A late response to the previous keepalive
+        assert_eq!(keepalive.on_response(Some(first)), None);
+        assert!(keepalive.on_response(Some(second)).is_some());
+        assert!(!keepalive.is_pending(second));
+        assert_eq!(keepalive.on_response(Some(second)), None);
+    }
+
+    #[test]
+    fn keepalive_reset() {
+        let mut keepalive = Keepalive::new(Duration::from_secs(30), Duration::from_secs(10));
+        let id = keepalive.sent();
+        keepalive.reset();
+        assert!(!keepalive.is_pending(id));
+        assert_eq!(keepalive.on_response(Some(id)), None);
+    }
+}